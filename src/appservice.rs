@@ -1,4 +1,12 @@
-use crate::{config::Config, database::cache::CacheDb, MESSAGES_SEMPAHORE};
+use crate::{
+    config::Config,
+    database::{
+        aliases::AliasSource,
+        cache::{CacheDb, CrawlCounter},
+    },
+    webpage::metrics::{permit_in_use, JOIN_ATTEMPTS, PAGINATION_PAGES},
+    MESSAGES_SEMPAHORE,
+};
 use chrono::{prelude::*, Duration as ChronoDuration};
 use matrix_sdk::{
     api::r0::config::get_global_account_data::Request as GlobalAccountDataGetRequest,
@@ -11,8 +19,13 @@ use matrix_sdk::{
     events::{
         direct::DirectEventContent,
         room::{
+            avatar::AvatarEventContent,
+            canonical_alias::CanonicalAliasEventContent,
+            join_rules::JoinRulesEventContent,
             member::{MemberEventContent, MembershipState},
             message::{MessageEventContent, MessageType, TextMessageEventContent},
+            name::NameEventContent,
+            tombstone::TombstoneEventContent,
         },
         AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, EventType, RawExt, SyncMessageEvent,
         SyncStateEvent,
@@ -31,14 +44,15 @@ use tracing::{error, info, span, warn, Instrument, Level};
 pub async fn generate_appservice(config: &Config, cache: CacheDb) -> Appservice {
     let homeserver_url = config.bot.clone().homeserver_url;
     let server_name = config.bot.clone().server_name;
-    let registration = AppserviceRegistration::try_from_yaml_file("./registration.yaml").unwrap();
+    let registration =
+        AppserviceRegistration::try_from_yaml_file(&config.storage.registration_path).unwrap();
 
     let mut appservice = Appservice::new_with_config(
         homeserver_url.as_str(),
         server_name.as_str(),
         registration.clone(),
         ClientConfig::default()
-            .store_path("./store_new/")
+            .store_path(&config.storage.store_path)
             .request_config(
                 RequestConfig::default()
                     .assert_identity()
@@ -207,6 +221,7 @@ impl VoyagerBot {
         };
     }
 
+    /// Looks for aliases in the message. Live messages get marked as read if they had any.
     #[tracing::instrument(skip(config, cache, client, room, msg_body))]
     #[async_recursion::async_recursion]
     async fn process_message(
@@ -215,7 +230,8 @@ impl VoyagerBot {
         client: Client,
         msg_body: &str,
         room: Joined,
-        event_id: Option<EventId>,
+        event_id: EventId,
+        mark_read: bool,
     ) {
         // Early exist if there are no regex matches
         if !REGEX.is_match(msg_body) {
@@ -225,6 +241,7 @@ impl VoyagerBot {
         // Iterate over aliases
         for cap in REGEX.captures_iter(msg_body) {
             let room_alias = cap[0].to_string();
+            cache.increment_counter(CrawlCounter::AliasesDiscovered);
 
             let client = client.clone();
             let room = room.clone();
@@ -241,13 +258,14 @@ impl VoyagerBot {
                     client,
                     room_alias,
                     room,
+                    event_id.clone(),
                 )
                 .instrument(span),
             );
         }
 
         // If there is a match mark the event as read to indicate it worked
-        if let Some(event_id) = event_id {
+        if mark_read {
             if let Err(e) = room.read_marker(&event_id, Some(&event_id)).await {
                 error!("Can't send read marker event: {}", e);
             };
@@ -261,6 +279,7 @@ impl VoyagerBot {
         client: Client,
         room_alias: String,
         parent_room: Joined,
+        event_id: EventId,
     ) {
         // Workaround for: https://github.com/matrix-org/synapse/issues/10021
         if room_alias == "#emacs:matrix.org"
@@ -275,6 +294,7 @@ impl VoyagerBot {
         }
 
         // Try to join and give it max 5 tries to do so.
+        cache.increment_counter(CrawlCounter::JoinsAttempted);
         let mut tries: u8 = 5;
         let mut room = None;
         warn!("Trying to join {}", room_alias);
@@ -283,7 +303,7 @@ impl VoyagerBot {
                 warn!("No retries left for {}", room_alias);
                 break;
             }
-            room = VoyagerBot::try_join(client.clone(), &room_alias).await;
+            room = VoyagerBot::try_join(client.clone(), &cache, &room_alias).await;
             warn!("{} retries left for {}", tries, room_alias);
             tries -= 1;
         }
@@ -291,9 +311,11 @@ impl VoyagerBot {
         // Do not continue if room not found
         if room.is_none() {
             warn!("Didnt get room for {}", room_alias);
+            cache.increment_counter(CrawlCounter::JoinsFailed);
             return;
         } else {
             info!("Got room for {}", room_alias);
+            cache.increment_counter(CrawlCounter::JoinsSucceeded);
         }
 
         // Access room_id once
@@ -301,14 +323,101 @@ impl VoyagerBot {
         let room = room.unwrap().clone();
         let clone_room = room.clone();
         let room_id = clone_room.room_id();
+        VoyagerBot::record_aliases(&cache, &room, &room_alias);
 
         // Save room to db
-        if VoyagerBot::save_to_db(&cache, room_alias.clone(), room_id.as_str(), parent_room).await {
+        let known = VoyagerBot::save_to_db(
+            &cache,
+            room_alias.clone(),
+            room_id.as_str(),
+            parent_room,
+            &event_id,
+        )
+        .await;
+
+        // Upgraded rooms are replaced by their successor instead of being crawled
+        if let Some(tombstone) = room.tombstone() {
+            VoyagerBot::follow_tombstone(
+                &cache,
+                client,
+                room_id,
+                &tombstone.replacement_room,
+                None,
+            )
+            .await;
+            return;
+        }
+        if known {
             return;
         }
         VoyagerBot::fetch_messages(room_id, room, client, config, cache).await;
     }
 
+    /// Adds the mentioned alias and the aliases published by the room to the alias index
+    #[tracing::instrument(skip(cache, room))]
+    fn record_aliases(cache: &CacheDb, room: &Joined, mentioned_alias: &str) {
+        let mut aliases = Vec::new();
+        if mentioned_alias.starts_with('#') {
+            aliases.push((mentioned_alias.to_string(), AliasSource::Mention));
+        }
+        if let Some(alias) = room.canonical_alias() {
+            aliases.push((alias.to_string(), AliasSource::Canonical));
+        }
+        for alias in room.alt_aliases() {
+            aliases.push((alias.to_string(), AliasSource::AltAlias));
+        }
+
+        for (alias, source) in aliases {
+            if let Err(e) = cache
+                .aliases
+                .record(&alias, room.room_id().as_str(), source)
+            {
+                error!("Failed to save alias {}: {}", alias, e);
+            }
+        }
+    }
+
+    /// Joins the replacement of an upgraded room and moves the links of the room to it
+    #[tracing::instrument(skip(cache, client))]
+    async fn follow_tombstone(
+        cache: &CacheDb,
+        client: Client,
+        room_id: &RoomId,
+        replacement_room: &RoomId,
+        event_id: Option<&EventId>,
+    ) {
+        if !cache.graph.knows_room(room_id.as_str()) {
+            return;
+        }
+        if VoyagerBot::try_join(client, cache, replacement_room.as_str())
+            .await
+            .is_none()
+        {
+            warn!(
+                "Failed to join replacement {} of {}",
+                replacement_room, room_id
+            );
+            return;
+        }
+
+        if let Err(e) = cache
+            .graph
+            .replace_room(
+                room_id.as_str(),
+                replacement_room.as_str(),
+                event_id.map(|x| x.as_str()),
+            )
+            .await
+        {
+            error!("Failed to move links to the replacement room: {}", e);
+            return;
+        }
+        info!(
+            "Moved links of {} to its replacement {}",
+            room_id, replacement_room
+        );
+    }
+
     #[tracing::instrument(skip(room, client, config, cache))]
     async fn fetch_messages(
         room_id: &RoomId,
@@ -319,6 +428,7 @@ impl VoyagerBot {
     ) {
         let mut resp = None;
         if let Ok(_guard) = MESSAGES_SEMPAHORE.acquire().await {
+            let _in_use = permit_in_use();
             // Prepare messages request
             let mut request = MessagesRequest::new(room_id, "", Direction::Backward);
             request.limit = uint!(60);
@@ -329,6 +439,7 @@ impl VoyagerBot {
         };
         match resp {
             Some(Ok(resp)) => {
+                PAGINATION_PAGES.inc();
                 // Iterate as long as tokens arent the same
                 let mut chunk = resp.chunk;
                 let mut failed = false;
@@ -346,6 +457,7 @@ impl VoyagerBot {
                         {
                             // Ignore messages sent by us
                             let sender = message.sender;
+                            let event_id = message.event_id;
                             if client.user_id().await.unwrap() == sender {
                                 continue;
                             }
@@ -373,7 +485,8 @@ impl VoyagerBot {
                                                 client,
                                                 &text_content.body,
                                                 parent_room,
-                                                None,
+                                                event_id,
+                                                false,
                                             )
                                             .instrument(span)
                                             .await;
@@ -396,12 +509,14 @@ impl VoyagerBot {
                     let tries: i32 = 0;
                     while tries <= 5 && end == old_end {
                         if let Ok(_guard) = MESSAGES_SEMPAHORE.acquire().await {
+                            let _in_use = permit_in_use();
                             let mut request =
                                 MessagesRequest::new(room_id, &end, Direction::Backward);
                             request.limit = uint!(60);
                             request.filter = Some(MESSAGES_FILTER.clone());
                             let previous = room.messages(request).await;
                             if let Ok(previous) = previous {
+                                PAGINATION_PAGES.inc();
                                 // Set new chunk to make sure we iterate the correct data in the next round
                                 chunk = previous.chunk;
                                 from = end;
@@ -703,13 +818,19 @@ impl VoyagerBot {
         None
     }
 
-    #[tracing::instrument(skip(client))]
-    pub async fn try_join(client: Client, room_alias: &str) -> Option<Joined> {
+    #[tracing::instrument(skip(client, cache))]
+    pub async fn try_join(client: Client, cache: &CacheDb, room_alias: &str) -> Option<Joined> {
+        // Aliases we have seen before resolve without asking the server
+        let known_room_id = cache.aliases.get(room_alias).map(|entry| entry.room_id);
+
         // Check if we already knew the room and exit early if it is the case
         let already_joined_room_id = client
             .joined_rooms()
             .iter()
             .find(|room| {
+                if known_room_id.as_deref() == Some(room.room_id().as_str()) {
+                    return true;
+                }
                 if let Some(alias) = room.canonical_alias() {
                     return alias == room_alias;
                 };
@@ -717,9 +838,13 @@ impl VoyagerBot {
             })
             .cloned();
         if already_joined_room_id.is_some() {
+            JOIN_ATTEMPTS.with_label_values(&["already_joined"]).inc();
             return already_joined_room_id;
         }
-        VoyagerBot::join_via_server(client, room_alias).await
+        let room = VoyagerBot::join_via_server(client, room_alias).await;
+        let outcome = if room.is_some() { "joined" } else { "failed" };
+        JOIN_ATTEMPTS.with_label_values(&[outcome]).inc();
+        room
     }
 
     #[tracing::instrument(skip(cache, parent_room))]
@@ -729,6 +854,7 @@ impl VoyagerBot {
         room_alias: String,
         room_id: &str,
         parent_room: Joined,
+        event_id: &EventId,
     ) -> bool {
         // Get parent Data
         let parent_id = parent_room.room_id().as_str();
@@ -737,6 +863,11 @@ impl VoyagerBot {
         if parent_id.is_empty() {
             return true;
         }
+        // Rooms removed by an admin are not added again
+        if cache.graph.is_blocked(room_id) || cache.graph.is_blocked(parent_id) {
+            info!("Skipping relation to blocked room: {}", room_alias);
+            return true;
+        }
         // Check if we know the child already
         if cache.graph.knows_room(room_id) {
             // Check if the parent was known for this child already
@@ -747,14 +878,28 @@ impl VoyagerBot {
                     "New room relation for already known room: {:?} -> {}",
                     parent_displayname, room_alias
                 );
-                if let Err(e) = cache.graph.add_child(parent_id, room_id).await {
+                if let Err(e) = cache
+                    .graph
+                    .add_child(parent_id, room_id, event_id.as_str())
+                    .await
+                {
                     error!("failed to save child: {}", e);
                 };
+            } else if let Err(e) =
+                cache
+                    .graph
+                    .record_mention(parent_id, room_id, Some(event_id.as_str()))
+            {
+                error!("failed to save mention: {}", e);
             }
             return true;
         } else {
             // Save it as it is a new relation
-            if let Err(e) = cache.graph.add_child(parent_id, room_id).await {
+            if let Err(e) = cache
+                .graph
+                .add_child(parent_id, room_id, event_id.as_str())
+                .await
+            {
                 error!("failed to save child: {}", e);
             };
 
@@ -826,12 +971,25 @@ impl VoyagerBot {
                 .expect("Can't send typing event");
         }
     }
+
+    /// Updates the node when the topic in the room state differs from the stored one.
+    /// m.room.topic events don't reach the handler so this runs for every member and message event.
+    #[tracing::instrument(skip(self, room))]
+    async fn check_topic(&self, room: &Room) {
+        let room_id = room.room_id().as_str();
+        if let Some(meta) = self.cache.graph.get_room_meta(room_id) {
+            if meta.topic != room.topic().unwrap_or_default() {
+                self.cache.graph.update_room(room_id).await;
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl EventHandler for VoyagerBot {
     #[tracing::instrument(skip(self, room, event))]
     async fn on_room_member(&self, room: Room, event: &SyncStateEvent<MemberEventContent>) {
+        self.check_topic(&room).await;
         if let MembershipState::Invite = event.content.membership {
             if !&event.state_key.contains("@server_stats:nordgedanken.dev") {
                 return;
@@ -863,8 +1021,83 @@ impl EventHandler for VoyagerBot {
         };
     }
 
+    #[tracing::instrument(skip(self, room, event))]
+    async fn on_room_tombstone(&self, room: Room, event: &SyncStateEvent<TombstoneEventContent>) {
+        let client = self.appservice.get_cached_client(None).unwrap();
+        let cache = self.cache.clone();
+        let room_id = room.room_id().clone();
+        let replacement_room = event.content.replacement_room.clone();
+        let event_id = event.event_id.clone();
+
+        let span = span!(
+            Level::INFO,
+            "Following tombstone",
+            room_id = room_id.as_str(),
+            replacement_room = replacement_room.as_str()
+        );
+        tokio::spawn(
+            async move {
+                VoyagerBot::follow_tombstone(
+                    &cache,
+                    client,
+                    &room_id,
+                    &replacement_room,
+                    Some(&event_id),
+                )
+                .await;
+            }
+            .instrument(span),
+        );
+    }
+
+    #[tracing::instrument(skip(self, room, _event))]
+    async fn on_room_name(&self, room: Room, _event: &SyncStateEvent<NameEventContent>) {
+        self.cache.graph.update_room(room.room_id().as_str()).await;
+    }
+
+    #[tracing::instrument(skip(self, room, _event))]
+    async fn on_room_avatar(&self, room: Room, _event: &SyncStateEvent<AvatarEventContent>) {
+        self.cache.graph.update_room(room.room_id().as_str()).await;
+    }
+
+    #[tracing::instrument(skip(self, room, event))]
+    async fn on_room_canonical_alias(
+        &self,
+        room: Room,
+        event: &SyncStateEvent<CanonicalAliasEventContent>,
+    ) {
+        let aliases = event
+            .content
+            .alias
+            .iter()
+            .map(|alias| (alias, AliasSource::Canonical))
+            .chain(
+                event
+                    .content
+                    .alt_aliases
+                    .iter()
+                    .map(|alias| (alias, AliasSource::AltAlias)),
+            );
+        for (alias, source) in aliases {
+            if let Err(e) =
+                self.cache
+                    .aliases
+                    .record(alias.as_str(), room.room_id().as_str(), source)
+            {
+                error!("Failed to save alias {}: {}", alias, e);
+            }
+        }
+        self.cache.graph.update_room(room.room_id().as_str()).await;
+    }
+
+    #[tracing::instrument(skip(self, room, _event))]
+    async fn on_room_join_rules(&self, room: Room, _event: &SyncStateEvent<JoinRulesEventContent>) {
+        self.cache.graph.update_room(room.room_id().as_str()).await;
+    }
+
     #[tracing::instrument(skip(event, room, self))]
     async fn on_room_message(&self, room: Room, event: &SyncMessageEvent<MessageEventContent>) {
+        self.check_topic(&room).await;
         if let Room::Joined(room) = room {
             let msg_body = if let SyncMessageEvent {
                 content:
@@ -896,12 +1129,7 @@ impl EventHandler for VoyagerBot {
             tokio::spawn(
                 async move {
                     VoyagerBot::process_message(
-                        config,
-                        cache,
-                        client,
-                        &msg_body,
-                        room,
-                        Some(event_id),
+                        config, cache, client, &msg_body, room, event_id, true,
                     )
                     .await;
                 }
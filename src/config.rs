@@ -1,11 +1,25 @@
-use serde::{Deserialize, Serialize};
+use crate::matrix::{ParsedVersion, ServerImplementation};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::SocketAddr;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub postgres: Postgres,
-    pub influxdb: InfluxDb,
+    /// Kept for older configs. Gets used as an additional influxdb2 sink.
+    #[serde(default)]
+    pub influxdb: Option<InfluxDb>,
+    #[serde(default)]
+    pub metrics: Vec<NamedMetricsSink>,
     pub bot: Bot,
     pub api: Api,
+    #[serde(default)]
+    pub dns: Dns,
+    #[serde(default)]
+    pub advisories: Vec<Advisory>,
+    #[serde(default)]
+    pub polling: Polling,
+    #[serde(default)]
+    pub storage: Storage,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -20,6 +34,42 @@ pub struct InfluxDb {
     pub org: String,
     pub bucket: String,
 }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct InfluxDbV1 {
+    pub host: String,
+    pub database: String,
+    pub retention_policy: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FileSink {
+    pub path: String,
+}
+
+/// The name of the legacy influxdb sink
+pub const LEGACY_INFLUXDB_SINK: &str = "influxdb";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct NamedMetricsSink {
+    /// Points which could not be written get spooled under this name.
+    /// It has to be unique and should not change while points are spooled.
+    pub name: String,
+    #[serde(flatten)]
+    pub sink: MetricsSinkConfig,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MetricsSinkConfig {
+    InfluxDb2(InfluxDb),
+    InfluxDb1(InfluxDbV1),
+    /// Appends the points in line protocol to a file
+    File(FileSink),
+    Noop,
+}
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Bot {
     pub homeserver_url: String,
@@ -36,6 +86,198 @@ pub struct Api {
     pub ip: String,
     pub webpage_path: String,
     pub port: u16,
+    /// Bearer token for the admin endpoints. They are disabled if this is not set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Dns {
+    #[serde(default)]
+    pub resolver: DnsResolver,
+    /// Only used with the custom resolver
+    #[serde(default)]
+    pub nameservers: Vec<NameServer>,
+    /// Use one resolver for the whole job instead of one per server
+    #[serde(default = "default_true")]
+    pub shared: bool,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Dns {
+            resolver: DnsResolver::default(),
+            nameservers: vec![],
+            shared: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsResolver {
+    /// Uses /etc/resolv.conf
+    System,
+    /// Uses Quad9 via DNS-over-TLS
+    #[default]
+    Quad9,
+    /// Uses the configured nameservers
+    Custom,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct NameServer {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// Name the certificate of the nameserver gets validated against. Required for tls.
+    pub tls_dns_name: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Advisory {
+    pub implementation: ServerImplementation,
+    /// Servers running a version lower than this are affected
+    #[serde(
+        deserialize_with = "deserialize_version",
+        serialize_with = "serialize_version"
+    )]
+    pub minimum_version: ParsedVersion,
+    pub description: String,
+}
+
+/// Parses the version while loading so invalid advisories fail the startup
+fn deserialize_version<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<ParsedVersion, D::Error> {
+    let version = String::deserialize(deserializer)?;
+    ParsedVersion::parse(&version)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid version '{}'", version)))
+}
+
+fn serialize_version<S: Serializer>(
+    version: &ParsedVersion,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&version.to_string())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Polling {
+    /// Servers failing for longer than this are marked as dead
+    #[serde(default = "default_dead_after_hours")]
+    pub dead_after_hours: u64,
+    /// How often dead servers still get polled
+    #[serde(default = "default_dead_poll_interval_hours")]
+    pub dead_poll_interval_hours: u64,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling {
+            dead_after_hours: default_dead_after_hours(),
+            dead_poll_interval_hours: default_dead_poll_interval_hours(),
+        }
+    }
+}
+
+/// Locations of the state on disk. Relative paths are relative to the working directory.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Storage {
+    /// Directory of the sled database
+    #[serde(default = "default_cache_path")]
+    pub cache_path: String,
+    /// Directory of the matrix-sdk state and crypto store
+    #[serde(default = "default_store_path")]
+    pub store_path: String,
+    /// The appservice registration file
+    #[serde(default = "default_registration_path")]
+    pub registration_path: String,
+    /// How often sled flushes the cache to disk. Writes in between are committed together.
+    #[serde(default = "default_flush_every_ms")]
+    pub flush_every_ms: u64,
+    /// Upper limit of the points kept per failing metrics sink. The oldest ones get dropped first.
+    #[serde(default = "default_max_spooled_points")]
+    pub max_spooled_points: usize,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            cache_path: default_cache_path(),
+            store_path: default_store_path(),
+            registration_path: default_registration_path(),
+            flush_every_ms: default_flush_every_ms(),
+            max_spooled_points: default_max_spooled_points(),
+        }
+    }
+}
+
+impl Storage {
+    /// Makes sure the directories exist and are writable and the registration is readable.
+    ///
+    /// Missing directories get created.
+    #[tracing::instrument]
+    pub fn check(&self) -> Result<(), crate::errors::Errors> {
+        for dir in [&self.cache_path, &self.store_path].iter() {
+            check_writable_dir(dir)?;
+        }
+        std::fs::File::open(&self.registration_path).map_err(|e| {
+            crate::errors::Errors::StorageError(self.registration_path.clone(), e.to_string())
+        })?;
+        Ok(())
+    }
+}
+
+fn check_writable_dir(dir: &str) -> Result<(), crate::errors::Errors> {
+    let storage_error =
+        |e: std::io::Error| crate::errors::Errors::StorageError(dir.to_string(), e.to_string());
+    std::fs::create_dir_all(dir).map_err(storage_error)?;
+    let probe = std::path::Path::new(dir).join(".write_check");
+    std::fs::write(&probe, b"").map_err(storage_error)?;
+    std::fs::remove_file(&probe).map_err(storage_error)?;
+    Ok(())
+}
+
+fn default_cache_path() -> String {
+    "./storage/cache".to_string()
+}
+
+fn default_store_path() -> String {
+    "./store_new/".to_string()
+}
+
+fn default_registration_path() -> String {
+    "./registration.yaml".to_string()
+}
+
+fn default_flush_every_ms() -> u64 {
+    500
+}
+
+fn default_max_spooled_points() -> usize {
+    1_000_000
+}
+
+fn default_dead_after_hours() -> u64 {
+    72
+}
+
+fn default_dead_poll_interval_hours() -> u64 {
+    24
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl Config {
@@ -45,6 +287,19 @@ impl Config {
     ) -> Result<Self, crate::errors::Errors> {
         let contents = std::fs::read_to_string(path)?;
         let config: Self = serde_yaml::from_str(&contents)?;
+
+        let mut names = std::collections::HashSet::new();
+        if config.influxdb.is_some() {
+            names.insert(LEGACY_INFLUXDB_SINK);
+        }
+        for sink in &config.metrics {
+            // The name is part of the spool keys
+            if sink.name.is_empty() || sink.name.contains('/') || !names.insert(&sink.name) {
+                return Err(crate::errors::Errors::InvalidMetricsSinkName(
+                    sink.name.clone(),
+                ));
+            }
+        }
         Ok(config)
     }
 }
@@ -0,0 +1,109 @@
+use chrono::prelude::*;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Where an alias was seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AliasSource {
+    Canonical,
+    AltAlias,
+    /// Found in a message
+    Mention,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AliasEntry {
+    pub alias: String,
+    pub room_id: String,
+    pub sources: Vec<AliasSource>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+/// Maps every alias we have seen to the room it pointed to.
+///
+/// Entries are stored as `alias/{alias}`. `room/{room_id}/{alias}` keeps the aliases of a room.
+#[derive(Debug)]
+pub struct AliasIndex {
+    tree: sled::Tree,
+}
+
+impl AliasIndex {
+    #[tracing::instrument(name = "AliasIndex::new", skip(tree))]
+    pub fn new(tree: sled::Tree) -> Self {
+        AliasIndex { tree }
+    }
+
+    /// Records that the alias points to the room. Aliases which moved to another room get
+    /// a new entry.
+    #[tracing::instrument(skip(self))]
+    pub fn record(&self, alias: &str, room_id: &str, source: AliasSource) -> Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let previous =
+            self.tree
+                .fetch_and_update(format!("alias/{}", alias).as_bytes(), |value_opt| {
+                    let entry =
+                        match value_opt.and_then(|x| bincode::deserialize::<AliasEntry>(x).ok()) {
+                            Some(mut entry) if entry.room_id == room_id => {
+                                if !entry.sources.contains(&source) {
+                                    entry.sources.push(source);
+                                    entry.sources.sort();
+                                }
+                                entry.last_seen = now;
+                                entry
+                            }
+                            _ => AliasEntry {
+                                alias: alias.to_string(),
+                                room_id: room_id.to_string(),
+                                sources: vec![source],
+                                first_seen: now,
+                                last_seen: now,
+                            },
+                        };
+                    Some(sled::IVec::from(bincode::serialize(&entry).unwrap()))
+                })?;
+
+        if let Some(previous) = previous {
+            let previous: AliasEntry = bincode::deserialize(previous.as_ref())?;
+            if previous.room_id != room_id {
+                self.tree
+                    .remove(format!("room/{}/{}", previous.room_id, alias).as_bytes())?;
+            }
+        }
+        self.tree.insert(
+            format!("room/{}/{}", room_id, alias).as_bytes(),
+            Vec::<u8>::new(),
+        )?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get(&self, alias: &str) -> Option<AliasEntry> {
+        match self.tree.get(format!("alias/{}", alias).as_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(bytes.as_ref()).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to get alias: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns all aliases which currently point to the room
+    #[tracing::instrument(skip(self))]
+    pub fn get_room_aliases(&self, room_id: &str) -> Result<Vec<AliasEntry>> {
+        let prefix = format!("room/{}/", room_id);
+        let mut aliases = Vec::new();
+        for res in self.tree.scan_prefix(prefix.as_bytes()).keys() {
+            let key = res?;
+            let alias = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+            if let Some(entry) = self.get(&alias) {
+                aliases.push(entry);
+            }
+        }
+        Ok(aliases)
+    }
+}
@@ -1,51 +1,135 @@
-use std::sync::Arc;
+use std::{convert::TryInto, sync::Arc};
 
+use chrono::prelude::*;
 use color_eyre::Result;
 use sled::IVec;
 use sqlx::PgPool;
 use tokio::sync::watch::Sender;
 
-use crate::database::graph::GraphDb;
-use crate::matrix::MatrixVersionServer;
-use crate::webpage::api::SSEJson;
+use crate::config::Storage;
+use crate::database::{aliases::AliasIndex, graph::GraphDb, migrations};
+use crate::matrix::{
+    FlaggedServer, MatrixServerKeys, MatrixSsServername, MatrixVersionServer, Reachability,
+    VersionHistoryEntry,
+};
+use crate::webpage::api::WsMessage;
 use tracing::{error, info};
 
+/// Counters for the activity of the crawler. They are kept across restarts.
+#[derive(Debug, Clone, Copy)]
+pub enum CrawlCounter {
+    JoinsAttempted,
+    JoinsSucceeded,
+    JoinsFailed,
+    AliasesDiscovered,
+}
+
+impl CrawlCounter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CrawlCounter::JoinsAttempted => "joins_attempted",
+            CrawlCounter::JoinsSucceeded => "joins_succeeded",
+            CrawlCounter::JoinsFailed => "joins_failed",
+            CrawlCounter::AliasesDiscovered => "aliases_discovered",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CacheDb {
     db: Arc<sled::Db>,
     pub graph: Arc<GraphDb>,
+    pub aliases: Arc<AliasIndex>,
 }
 
 impl CacheDb {
     #[tracing::instrument(name = "CacheDb::new", skip(tx))]
-    pub fn new(tx: Sender<Option<SSEJson>>, pool: PgPool) -> Self {
+    pub fn new(tx: Sender<Option<WsMessage>>, pool: PgPool, storage: &Storage) -> Self {
         info!("Created new db");
-        let db = sled::Config::default()
-            .path("./storage/cache".to_owned())
-            .use_compression(true)
-            .open()
-            .unwrap();
+        let db = CacheDb::open(storage).unwrap();
         let hash_map = db.open_tree(b"hash_map").unwrap();
         let state = db.open_tree(b"state").unwrap();
         let parent_child = db.open_tree(b"parent_child").unwrap();
         let child_parent = db.open_tree(b"child_parent").unwrap();
+        let edges = db.open_tree(b"edges").unwrap();
+        let blocked = db.open_tree(b"blocked_rooms").unwrap();
         let graph = Arc::new(GraphDb::new(
             hash_map,
             state,
             parent_child,
             child_parent,
+            edges,
+            blocked,
             tx,
             pool,
         ));
+        let aliases = Arc::new(AliasIndex::new(db.open_tree(b"aliases").unwrap()));
         let db = Arc::new(db);
-        CacheDb { db, graph }
+        CacheDb { db, graph, aliases }
     }
 
+    /// Opens the sled database without setting up the trees. Used by the schema commands too.
+    #[tracing::instrument]
+    pub fn open(storage: &Storage) -> sled::Result<sled::Db> {
+        sled::Config::default()
+            .path(&storage.cache_path)
+            .use_compression(true)
+            .flush_every_ms(Some(storage.flush_every_ms))
+            .open()
+    }
+
+    /// Runs the pending migrations of the on-disk schema
+    #[tracing::instrument(skip(self))]
+    pub fn migrate(&self) -> Result<()> {
+        let applied = migrations::migrate(&self.db)?;
+        if applied > 0 {
+            info!(
+                "Applied {} migrations. Schema version is now {}",
+                applied,
+                migrations::SCHEMA_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes everything which was not flushed by sled yet to disk
     #[tracing::instrument(skip(self))]
-    pub fn set_server_address(&self, server_name: &str, server_address: String) -> Result<()> {
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_server_address(
+        &self,
+        server_name: &str,
+        server_address: &MatrixSsServername,
+    ) -> Result<()> {
+        let server_address_bytes = bincode::serialize(server_address)?;
         self.db.insert(
             format!("address/{}", server_name).as_bytes(),
-            server_address.as_bytes(),
+            server_address_bytes,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Keeps the previous address whenever the resolution of a server changed
+    #[tracing::instrument(skip(self))]
+    pub fn record_address_change(
+        &self,
+        server_name: &str,
+        previous: &MatrixSsServername,
+        current: &MatrixSsServername,
+    ) -> Result<()> {
+        let change_bytes = bincode::serialize(&(previous, current))?;
+        self.db.insert(
+            format!(
+                "address_history/{}/{:020}",
+                server_name, current.resolved_at
+            )
+            .as_bytes(),
+            change_bytes,
         )?;
         self.db.flush()?;
         Ok(())
@@ -62,10 +146,258 @@ impl CacheDb {
             format!("version/{}", server_name).as_bytes(),
             server_version_bytes,
         )?;
+        self.update_version_history(server_name, server_version)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Extends the last history entry if the version did not change or appends a new one
+    #[tracing::instrument(skip(self))]
+    fn update_version_history(
+        &self,
+        server_name: &str,
+        server_version: MatrixVersionServer,
+    ) -> Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let prefix = format!("version_history/{}/", server_name);
+        if let Some((key, bytes)) = self
+            .db
+            .scan_prefix(prefix.as_bytes())
+            .next_back()
+            .transpose()?
+        {
+            let mut entry: VersionHistoryEntry = bincode::deserialize(bytes.as_ref())?;
+            if entry.server == server_version {
+                entry.last_seen = now;
+                self.db.insert(key, bincode::serialize(&entry)?)?;
+                return Ok(());
+            }
+        }
+
+        let entry = VersionHistoryEntry {
+            server: server_version,
+            first_seen: now,
+            last_seen: now,
+        };
+        self.db.insert(
+            format!("{}{:020}", prefix, now).as_bytes(),
+            bincode::serialize(&entry)?,
+        )?;
+        Ok(())
+    }
+
+    /// Returns all versions a server was seen with, oldest first
+    #[tracing::instrument(skip(self))]
+    pub fn get_version_history(&self, server_name: &str) -> Result<Vec<VersionHistoryEntry>> {
+        let prefix = format!("version_history/{}/", server_name);
+        let mut history = Vec::new();
+        for res in self.db.scan_prefix(prefix.as_bytes()).values() {
+            let bytes = res?;
+            history.push(bincode::deserialize(bytes.as_ref())?);
+        }
+        Ok(history)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_server_keys(&self, server_name: &str, server_keys: &MatrixServerKeys) -> Result<()> {
+        let server_keys_bytes = bincode::serialize(server_keys)?;
+        self.db.insert(
+            format!("keys/{}", server_name).as_bytes(),
+            server_keys_bytes,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Keeps the previous keys whenever the verify keys of a server changed
+    #[tracing::instrument(skip(self))]
+    pub fn record_key_rotation(
+        &self,
+        server_name: &str,
+        previous: &MatrixServerKeys,
+        current: &MatrixServerKeys,
+    ) -> Result<()> {
+        let rotation_bytes = bincode::serialize(&(previous, current))?;
+        self.db.insert(
+            format!("key_history/{}/{:020}", server_name, current.fetched_at).as_bytes(),
+            rotation_bytes,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_server_keys(&self, server_name: &str) -> Result<Option<MatrixServerKeys>> {
+        match self.db.get(format!("keys/{}", server_name).as_bytes()) {
+            Ok(res) => {
+                if let Some(bytes) = res {
+                    let server_keys: MatrixServerKeys = bincode::deserialize(bytes.as_ref())?;
+                    return Ok(Some(server_keys));
+                }
+            }
+            Err(e) => error!("Failed to get keys: {}", e),
+        }
+
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self, reachability))]
+    pub fn set_reachability(&self, server_name: &str, reachability: &Reachability) -> Result<()> {
+        let reachability_bytes = bincode::serialize(reachability)?;
+        self.db.insert(
+            format!("reachability/{}", server_name).as_bytes(),
+            reachability_bytes,
+        )?;
         self.db.flush()?;
         Ok(())
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_reachability(&self, server_name: &str) -> Result<Option<Reachability>> {
+        match self
+            .db
+            .get(format!("reachability/{}", server_name).as_bytes())
+        {
+            Ok(res) => {
+                if let Some(bytes) = res {
+                    let reachability: Reachability = bincode::deserialize(bytes.as_ref())?;
+                    return Ok(Some(reachability));
+                }
+            }
+            Err(e) => error!("Failed to get reachability: {}", e),
+        }
+
+        Ok(None)
+    }
+
+    /// Unknown servers are not considered dead
+    #[tracing::instrument(skip(self))]
+    pub fn is_dead_server(&self, server_name: &str) -> bool {
+        match self.get_reachability(server_name) {
+            Ok(Some(reachability)) => reachability.dead,
+            Ok(None) => false,
+            Err(e) => {
+                error!("Failed to decode reachability of {}: {}", server_name, e);
+                false
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, flagged_server))]
+    pub fn set_flagged_server(&self, flagged_server: &FlaggedServer) -> Result<()> {
+        let flagged_server_bytes = bincode::serialize(flagged_server)?;
+        self.db.insert(
+            format!("flagged/{}", flagged_server.server_name).as_bytes(),
+            flagged_server_bytes,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn remove_flagged_server(&self, server_name: &str) -> Result<()> {
+        if self
+            .db
+            .remove(format!("flagged/{}", server_name).as_bytes())?
+            .is_some()
+        {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn is_flagged_server(&self, server_name: &str) -> bool {
+        if let Ok(res) = self
+            .db
+            .contains_key(format!("flagged/{}", server_name).as_bytes())
+        {
+            return res;
+        }
+        false
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_flagged_servers(&self) -> Result<Vec<FlaggedServer>> {
+        let mut flagged_servers = Vec::new();
+        for res in self.db.scan_prefix(b"flagged/").values() {
+            let bytes = res?;
+            flagged_servers.push(bincode::deserialize(bytes.as_ref())?);
+        }
+        Ok(flagged_servers)
+    }
+
+    #[tracing::instrument(skip(self, lines))]
+    pub fn spool_points(&self, sink_id: &str, lines: &[String]) -> Result<()> {
+        let id = self.db.generate_id()?;
+        self.db.insert(
+            format!("spool/{}/{:020}", sink_id, id).as_bytes(),
+            bincode::serialize(lines)?,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Returns the spooled chunks of a sink oldest first together with their keys
+    #[tracing::instrument(skip(self))]
+    pub fn get_spooled_points(
+        &self,
+        sink_id: &str,
+    ) -> impl Iterator<Item = Result<(IVec, Vec<String>)>> + Send {
+        self.db
+            .scan_prefix(format!("spool/{}/", sink_id).as_bytes())
+            .map(|res| {
+                let (key, bytes) = res?;
+                let lines: Vec<String> = bincode::deserialize(bytes.as_ref())?;
+                Ok((key, lines))
+            })
+    }
+
+    /// Returns false if the chunk was already removed
+    #[tracing::instrument(skip(self))]
+    pub fn remove_spooled_points(&self, key: &[u8]) -> Result<bool> {
+        let removed = self.db.remove(key)?.is_some();
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn count_spooled_points(&self, sink_id: &str) -> Result<usize> {
+        let mut count = 0;
+        for res in self.get_spooled_points(sink_id) {
+            count += res?.1.len();
+        }
+        Ok(count)
+    }
+
+    /// This is called for every alias so it does not flush explicitly
+    #[tracing::instrument(skip(self))]
+    pub fn increment_counter(&self, counter: CrawlCounter) {
+        if let Err(e) =
+            self.db
+                .update_and_fetch(format!("counter/{}", counter.as_str()).as_bytes(), |old| {
+                    let count = old
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .map(u64::from_be_bytes)
+                        .unwrap_or(0);
+                    Some((count + 1).to_be_bytes().to_vec())
+                })
+        {
+            error!("Failed to increment {}: {}", counter.as_str(), e);
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_counter(&self, counter: CrawlCounter) -> Result<u64> {
+        let count = self
+            .db
+            .get(format!("counter/{}", counter.as_str()).as_bytes())?
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Ok(count)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn contains_server(&self, server_name: &str) -> bool {
         if let Ok(res) = self
@@ -77,12 +409,16 @@ impl CacheDb {
         false
     }
 
+    /// Returns None for unknown servers and for entries which can not be decoded
     #[tracing::instrument(skip(self))]
-    pub fn get_server_address(&self, server_name: &str) -> Option<IVec> {
-        if let Ok(res) = self.db.get(format!("address/{}", server_name).as_bytes()) {
-            return res;
-        } else {
-            error!("Failed to get Server from sled");
+    pub fn get_server_address(&self, server_name: &str) -> Option<MatrixSsServername> {
+        match self.db.get(format!("address/{}", server_name).as_bytes()) {
+            Ok(Some(bytes)) => match bincode::deserialize(bytes.as_ref()) {
+                Ok(server_address) => return Some(server_address),
+                Err(e) => error!("Failed to decode address of {}: {}", server_name, e),
+            },
+            Ok(None) => {}
+            Err(e) => error!("Failed to get Server from sled: {}", e),
         }
         None
     }
@@ -102,6 +438,19 @@ impl CacheDb {
         Ok(None)
     }
 
+    /// Returns the last known version of every server
+    #[tracing::instrument(skip(self))]
+    pub fn get_all_versions(&self) -> Result<Vec<(String, MatrixVersionServer)>> {
+        let prefix = "version/";
+        let mut versions = Vec::new();
+        for res in self.db.scan_prefix(prefix.as_bytes()) {
+            let (key, bytes) = res?;
+            let server_name = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+            versions.push((server_name, bincode::deserialize(bytes.as_ref())?));
+        }
+        Ok(versions)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_all_addresses(
         &self,
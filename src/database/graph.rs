@@ -1,7 +1,23 @@
-use crate::webpage::api::{Link, RelationsJson, RoomRelation, SSEJson, ServersJson};
+use crate::database::snapshot::RelationsSnapshot;
+use crate::webpage::api::{
+    Link, NeighbourJson, RelationsJson, RemovedJson, RemovedLink, RoomJson, RoomRelation, SSEJson,
+    ServersJson, UpdatedJson, WsMessage,
+};
+use chrono::prelude::*;
 use color_eyre::Result;
-use matrix_sdk::{identifiers::RoomId, room::Joined};
-use sled::{IVec, Iter};
+use matrix_sdk::{
+    api::r0::alias::get_alias::Request as GetAliasRequest,
+    identifiers::{RoomAliasId, RoomId},
+    room::Joined,
+};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionResult,
+        TransactionalTree,
+    },
+    IVec, Iter, Transactional,
+};
 use sqlx::PgPool;
 use std::{
     borrow::Cow,
@@ -12,17 +28,94 @@ use std::{
 use tokio::sync::watch::Sender;
 use tracing::error;
 
-type RelationsMix = Vec<((String, String), BTreeSet<u128>)>;
+type RelationsMix = Vec<((u128, String), Vec<u128>)>;
+type TxResult<T = ()> = ConflictableTransactionResult<T, bincode::Error>;
 
 #[derive(Debug)]
 pub struct GraphDb {
     hash_map: sled::Tree,
+    /// Last known details of the rooms keyed by the room hash
     state: sled::Tree,
     parent_child: sled::Tree,
     child_parent: sled::Tree,
-    websocket_tx: Sender<Option<SSEJson>>,
+    /// Metadata of the links keyed by the parent hash followed by the child hash
+    edges: sled::Tree,
+    /// Rooms removed by an admin keyed by the room hash. They don't get added again.
+    blocked: sled::Tree,
+    websocket_tx: Sender<Option<WsMessage>>,
     pool: PgPool,
     room_name_cache: RwLock<BTreeMap<String, String>>,
+    /// Built on the first request of the relations
+    relations: RwLock<Option<RelationsSnapshot>>,
+}
+
+/// Size of the room graph as it is stored in sled
+#[derive(Debug, Clone, Copy)]
+pub struct GraphStats {
+    pub rooms: i64,
+    pub links: i64,
+    /// Only counts spaces the bot is joined to
+    pub spaces: i64,
+}
+
+/// Metadata of a link between two rooms.
+///
+/// Links created before this was tracked have no metadata.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EdgeMeta {
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// How often the child was mentioned in the parent
+    pub mentions: u64,
+    /// The event which first mentioned the child. Not known for links to replacement rooms
+    /// found in the room state.
+    pub event_id: Option<String>,
+}
+
+impl EdgeMeta {
+    /// Combines the metadata of two links between the same rooms
+    pub fn merge(self, other: EdgeMeta) -> EdgeMeta {
+        let (first, second) = if self.first_seen <= other.first_seen {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        EdgeMeta {
+            first_seen: first.first_seen,
+            last_seen: first.last_seen.max(second.last_seen),
+            mentions: first.mentions.saturating_add(second.mentions),
+            event_id: first.event_id.or(second.event_id),
+        }
+    }
+}
+
+/// Last known details of a room, stored in the state tree.
+///
+/// Keeps rooms in the graph after the bot left them or the SDK store lost them.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoomMeta {
+    pub name: String,
+    /// The canonical alias or the room ID if there is none
+    pub alias: String,
+    pub avatar: String,
+    pub topic: String,
+    pub is_space: bool,
+    pub members: i64,
+    /// Like `public` or `invite`
+    pub join_rule: String,
+    pub tombstoned: bool,
+    /// Unix timestamp in milliseconds of the last change
+    pub updated: i64,
+}
+
+impl RoomMeta {
+    /// Compares everything except the timestamp
+    pub fn same_details(&self, other: &RoomMeta) -> bool {
+        RoomMeta {
+            updated: other.updated,
+            ..self.clone()
+        } == *other
+    }
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
@@ -39,14 +132,17 @@ struct Server {
 impl GraphDb {
     #[tracing::instrument(
         name = "GraphDb::new",
-        skip(hash_map, state, parent_child, child_parent, tx)
+        skip(hash_map, state, parent_child, child_parent, edges, blocked, tx)
     )]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         hash_map: sled::Tree,
         state: sled::Tree,
         parent_child: sled::Tree,
         child_parent: sled::Tree,
-        tx: Sender<Option<SSEJson>>,
+        edges: sled::Tree,
+        blocked: sled::Tree,
+        tx: Sender<Option<WsMessage>>,
         pool: PgPool,
     ) -> Self {
         GraphDb {
@@ -54,14 +150,17 @@ impl GraphDb {
             state,
             parent_child,
             child_parent,
+            edges,
+            blocked,
             websocket_tx: tx,
             pool,
             room_name_cache: RwLock::new(BTreeMap::new()),
+            relations: RwLock::new(None),
         }
     }
 
     #[tracing::instrument(skip(self))]
-    async fn get_synapse_joined_members_count(&self) -> BTreeMap<String, i64> {
+    pub async fn get_synapse_joined_members_count(&self) -> BTreeMap<String, i64> {
         let res = sqlx::query_as(
             "SELECT room_id, COUNT(*) FROM current_state_events WHERE membership = 'join' AND type = 'm.room.member' GROUP by room_id;"
         )
@@ -123,156 +222,498 @@ impl GraphDb {
     }
 
     #[tracing::instrument(skip(self))]
-    pub async fn add_child(&self, parent: &str, child: &str) -> Result<()> {
+    pub async fn add_child(&self, parent: &str, child: &str, event_id: &str) -> Result<()> {
         let parent_hash = GraphDb::hash(parent);
         let child_hash = GraphDb::hash(child);
 
-        // Make sure we mapped both already
-        if let Ok(res) = self.hash_map.contains_key(parent_hash.to_le_bytes()) {
-            if !res {
-                self.map_hash_to_room_id(parent_hash, parent)?;
-            }
-        } else {
-            self.map_hash_to_room_id(parent_hash, parent)?;
+        // Save relation
+        if !self.insert_link(parent, child, Some(event_id))? {
+            return Ok(());
         }
 
-        if let Ok(res) = self.hash_map.contains_key(child_hash.to_le_bytes()) {
-            if !res {
-                self.map_hash_to_room_id(child_hash, child)?;
+        if let Some(client) = crate::MATRIX_CLIENT.get() {
+            let joined_rooms = client.joined_rooms();
+            let joined_members = self.get_synapse_joined_members_count().await;
+            if self.snapshot_misses_node(parent_hash) {
+                self.refresh_node(parent_hash, parent, &joined_rooms, &joined_members)
+                    .await;
+            }
+            let node = self
+                .refresh_node(child_hash, child, &joined_rooms, &joined_members)
+                .await;
+            if let Some(mut node) = node {
+                if base64::encode(parent_hash.to_le_bytes()) == "4u98GV1CGlCn6PvxBerjrw==" {
+                    return Ok(());
+                }
+                node.weight = Some(1);
+                let sse_json = SSEJson {
+                    node: Arc::new(node),
+                    link: Arc::new(self.link(parent_hash, child_hash)),
+                };
+                if let Err(e) = self.websocket_tx.send(Some(WsMessage::Added(sse_json))) {
+                    error!("Failed to broadcast to websockets: {:?}", e);
+                }
             }
-        } else {
-            self.map_hash_to_room_id(child_hash, child)?;
         }
 
-        // Save relation
-        self.parent_child
-            .update_and_fetch(parent_hash.to_le_bytes(), |value_opt| {
-                if let Some(existing) = value_opt {
-                    let mut decoded: Vec<u128> = bincode::deserialize(existing).unwrap();
+        Ok(())
+    }
 
-                    if !decoded.contains(&child_hash) {
-                        decoded.push(child_hash);
+    /// Maps both rooms, saves the link in both directions and counts the mention.
+    ///
+    /// Everything is written in one transaction so parent_child and child_parent can't get out
+    /// of sync. Flushing is left to sled which flushes every `flush_every_ms`.
+    ///
+    /// Returns false without writing anything if one of the rooms is blocked.
+    #[tracing::instrument(skip(self))]
+    fn insert_link(&self, parent: &str, child: &str, event_id: Option<&str>) -> Result<bool> {
+        let parent_hash = GraphDb::hash(parent);
+        let child_hash = GraphDb::hash(child);
+        let now = Utc::now().timestamp_millis();
+        let inserted = (
+            &self.hash_map,
+            &self.parent_child,
+            &self.child_parent,
+            &self.edges,
+            &self.blocked,
+        )
+            .transaction(
+                |(hash_map, parent_child, child_parent, edges, blocked)| -> TxResult<bool> {
+                    if blocked.get(parent_hash.to_le_bytes())?.is_some()
+                        || blocked.get(child_hash.to_le_bytes())?.is_some()
+                    {
+                        return Ok(false);
                     }
-                    Some(sled::IVec::from(bincode::serialize(&decoded).unwrap()))
-                } else {
-                    let data: Vec<u128> = vec![child_hash];
-
-                    Some(sled::IVec::from(bincode::serialize(&data).unwrap()))
-                }
-            })?;
-        self.parent_child.flush()?;
-        self.add_parent(parent_hash, child_hash)?;
+                    for (hash, room_id) in [(parent_hash, parent), (child_hash, child)].iter() {
+                        if hash_map.get(hash.to_le_bytes())?.is_none() {
+                            hash_map.insert(&hash.to_le_bytes()[..], room_id.as_bytes())?;
+                        }
+                    }
+                    GraphDb::push_hash(parent_child, parent_hash, child_hash)?;
+                    GraphDb::push_hash(child_parent, child_hash, parent_hash)?;
+                    GraphDb::count_mention(
+                        edges,
+                        GraphDb::edge_key(parent_hash, child_hash),
+                        now,
+                        event_id,
+                    )?;
+                    Ok(true)
+                },
+            )?;
+        if inserted {
+            self.snapshot_link(parent_hash, child_hash);
+        }
+        Ok(inserted)
+    }
 
-        if let Some(client) = crate::MATRIX_CLIENT.get() {
-            if let Some(room) = client.get_joined_room(&RoomId::try_from(child).unwrap()) {
-                let alias = if let Some(alias) = room.canonical_alias() {
-                    alias.to_string()
-                } else {
-                    child.to_string()
-                };
+    /// Removes the link between parent and child.
+    ///
+    /// Returns false if there was no such link. The rooms stay known until they get pruned.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_child(&self, parent: &str, child: &str) -> Result<bool> {
+        let parent_hash = GraphDb::hash(parent);
+        let child_hash = GraphDb::hash(child);
+        let removed = self.unlink(parent_hash, child_hash)?;
+        if removed {
+            self.broadcast_removal(&[], &[(parent_hash, child_hash)]);
+        }
+        Ok(removed)
+    }
 
-                let mut name = { self.room_name_cache.read().unwrap().get(child).cloned() };
-                if name.is_none() {
-                    name = if let Ok(name) = room.display_name().await {
-                        if name.is_empty() {
-                            Some(child.to_string())
-                        } else {
-                            self.room_name_cache
-                                .write()
-                                .unwrap()
-                                .insert(child.to_string(), name.clone());
-                            Some(name)
-                        }
-                    } else {
-                        Some(child.to_string())
+    /// Removes a room together with all links from and to it and blocks it from being added
+    /// again. Unknown rooms get blocked too.
+    ///
+    /// Returns false if the room was not known.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_room(&self, room_id: &str) -> Result<bool> {
+        let hash = GraphDb::hash(room_id);
+        let removed: TransactionResult<(bool, Vec<(u128, u128)>), bincode::Error> = (
+            &self.hash_map,
+            &self.state,
+            &self.parent_child,
+            &self.child_parent,
+            &self.edges,
+            &self.blocked,
+        )
+            .transaction(
+                |(hash_map, state, parent_child, child_parent, edges, blocked)| {
+                    let children: Vec<u128> = match parent_child.get(hash.to_le_bytes())? {
+                        Some(bytes) => GraphDb::decode(&bytes)?,
+                        None => vec![],
+                    };
+                    let parents: Vec<u128> = match child_parent.get(hash.to_le_bytes())? {
+                        Some(bytes) => GraphDb::decode(&bytes)?,
+                        None => vec![],
                     };
-                };
-                let name = name.unwrap();
 
-                let topic = if let Some(topic) = room.topic() {
-                    topic
-                } else {
-                    "".into()
-                };
-                let avatar_url = if let Some(avatar_url) = room.avatar_url() {
-                    avatar_url.to_string()
-                } else {
-                    "".into()
-                };
-                if name == "MTRNord"
-                    || base64::encode(parent_hash.to_le_bytes()) == "4u98GV1CGlCn6PvxBerjrw=="
-                {
-                    return Ok(());
-                }
-                let members = if let Some(members) =
-                    self.get_synapse_joined_members_count().await.get(child)
-                {
-                    *members
-                } else {
-                    0
-                };
-                let sse_json = SSEJson {
-                    node: Arc::new(RoomRelation {
-                        id: base64::encode(child_hash.to_le_bytes()),
-                        name,
-                        alias,
-                        avatar: avatar_url,
-                        topic,
-                        weight: Some(1),
-                        incoming_links: None,
-                        outgoing_links: None,
-                        room_id: child.into(),
-                        is_space: room.is_space(),
-                        members,
-                    }),
-                    link: Arc::new(Link {
-                        source: base64::encode(parent_hash.to_le_bytes()),
-                        target: base64::encode(child_hash.to_le_bytes()),
-                        value: 1,
-                    }),
-                };
-                if let Err(e) = self.websocket_tx.send(Some(sse_json)) {
-                    error!("Failed to broadcast to websockets: {:?}", e);
-                }
+                    let mut links = Vec::new();
+                    for child in children {
+                        GraphDb::remove_hash(child_parent, child, hash)?;
+                        edges.remove(&GraphDb::edge_key(hash, child)[..])?;
+                        links.push((hash, child));
+                    }
+                    // A link of the room to itself was already removed with the children
+                    for parent in parents.into_iter().filter(|x| *x != hash) {
+                        GraphDb::remove_hash(parent_child, parent, hash)?;
+                        edges.remove(&GraphDb::edge_key(parent, hash)[..])?;
+                        links.push((parent, hash));
+                    }
+                    parent_child.remove(&hash.to_le_bytes()[..])?;
+                    child_parent.remove(&hash.to_le_bytes()[..])?;
+
+                    let known = hash_map.remove(&hash.to_le_bytes()[..])?.is_some();
+                    state.remove(&hash.to_le_bytes()[..])?;
+                    blocked.insert(&hash.to_le_bytes()[..], room_id.as_bytes())?;
+                    Ok((known, links))
+                },
+            );
+        let (known, links) = removed?;
+
+        self.room_name_cache.write().unwrap().remove(room_id);
+        self.update_snapshot(|snapshot| {
+            for (parent, child) in &links {
+                snapshot.remove_link(*parent, *child);
             }
+            snapshot.remove_node(hash);
+        });
+        if known {
+            self.broadcast_removal(&[hash], &links);
         }
+        Ok(known)
+    }
 
+    /// Allows a room which was removed by an admin to be added again.
+    ///
+    /// Returns false if the room was not blocked.
+    #[tracing::instrument(skip(self))]
+    pub fn unblock_room(&self, room_id: &str) -> Result<bool> {
+        Ok(self
+            .blocked
+            .remove(GraphDb::hash(room_id).to_le_bytes())?
+            .is_some())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn is_blocked(&self, room_id: &str) -> bool {
+        match self
+            .blocked
+            .contains_key(GraphDb::hash(room_id).to_le_bytes())
+        {
+            Ok(blocked) => blocked,
+            Err(e) => {
+                error!("Failed to check the blocked rooms: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Moves all links of an upgraded room to its replacement.
+    ///
+    /// The old room keeps a single link to the replacement to record the predecessor.
+    #[tracing::instrument(skip(self))]
+    pub async fn replace_room(&self, old: &str, new: &str, event_id: Option<&str>) -> Result<()> {
+        let old_hash = GraphDb::hash(old);
+        let new_hash = GraphDb::hash(new);
+        if !self.insert_link(old, new, event_id)? {
+            return Ok(());
+        }
+
+        let mut moved = Vec::new();
+        let mut replaced = Vec::new();
+        for child in GraphDb::get_hashes(&self.parent_child, old_hash) {
+            if child == new_hash || child == old_hash {
+                continue;
+            }
+            self.move_link((old_hash, child), (new_hash, child))?;
+            moved.push((old_hash, child));
+            replaced.push((new_hash, child));
+        }
+        for parent in GraphDb::get_hashes(&self.child_parent, old_hash) {
+            if parent == new_hash || parent == old_hash {
+                continue;
+            }
+            self.move_link((parent, old_hash), (parent, new_hash))?;
+            moved.push((parent, old_hash));
+            replaced.push((parent, new_hash));
+        }
+
+        self.update_snapshot(|snapshot| snapshot.remove_node(old_hash));
+        self.broadcast_removal(&[old_hash], &moved);
+        self.broadcast_replacement(new_hash, new, &replaced).await;
         Ok(())
     }
 
+    /// Sends the replacement room together with each of its moved links to the websocket clients
     #[tracing::instrument(skip(self))]
-    fn add_parent(&self, parent: u128, child: u128) -> Result<()> {
-        self.child_parent
-            .update_and_fetch(child.to_le_bytes(), |value_opt| {
-                if let Some(existing) = value_opt {
-                    let mut decoded: Vec<u128> = bincode::deserialize(existing).unwrap();
-                    if !decoded.contains(&parent) {
-                        decoded.push(parent);
-                    }
+    async fn broadcast_replacement(&self, hash: u128, room_id: &str, links: &[(u128, u128)]) {
+        let client = match crate::MATRIX_CLIENT.get() {
+            Some(client) => client,
+            None => return,
+        };
+        let joined_rooms = client.joined_rooms();
+        let joined_members = self.get_synapse_joined_members_count().await;
+        let node = match self
+            .refresh_node(hash, room_id, &joined_rooms, &joined_members)
+            .await
+        {
+            Some(node) => Arc::new(node),
+            None => return,
+        };
+        for (parent, child) in links {
+            let sse_json = SSEJson {
+                node: node.clone(),
+                link: Arc::new(self.link(*parent, *child)),
+            };
+            if let Err(e) = self.websocket_tx.send(Some(WsMessage::Added(sse_json))) {
+                error!("Failed to broadcast to websockets: {:?}", e);
+            }
+        }
+    }
 
-                    Some(sled::IVec::from(bincode::serialize(&decoded).unwrap()))
-                } else {
-                    let data: Vec<u128> = vec![parent];
+    /// Replaces a link and merges its metadata into the one of the new link
+    #[tracing::instrument(skip(self))]
+    fn move_link(&self, from: (u128, u128), to: (u128, u128)) -> Result<()> {
+        (&self.parent_child, &self.child_parent, &self.edges).transaction(
+            |(parent_child, child_parent, edges)| -> TxResult {
+                GraphDb::remove_hash(parent_child, from.0, from.1)?;
+                GraphDb::remove_hash(child_parent, from.1, from.0)?;
+                GraphDb::push_hash(parent_child, to.0, to.1)?;
+                GraphDb::push_hash(child_parent, to.1, to.0)?;
 
-                    Some(sled::IVec::from(bincode::serialize(&data).unwrap()))
+                let meta = edges
+                    .remove(&GraphDb::edge_key(from.0, from.1)[..])?
+                    .and_then(|x| bincode::deserialize::<EdgeMeta>(x.as_ref()).ok());
+                if let Some(meta) = meta {
+                    let key = GraphDb::edge_key(to.0, to.1);
+                    let merged = match edges
+                        .get(key)?
+                        .and_then(|x| bincode::deserialize(x.as_ref()).ok())
+                    {
+                        Some(existing) => meta.merge(existing),
+                        None => meta,
+                    };
+                    edges.insert(&key[..], GraphDb::encode(&merged)?)?;
                 }
-            })?;
-        self.child_parent.flush()?;
+                Ok(())
+            },
+        )?;
+        self.update_snapshot(|snapshot| snapshot.remove_link(from.0, from.1));
+        self.snapshot_link(to.0, to.1);
         Ok(())
     }
 
-    #[tracing::instrument]
-    fn hash(input: &str) -> u128 {
-        xxhash_rust::xxh3::xxh3_128(input.as_bytes())
+    /// Removes rooms which are neither parent nor child of any other room
+    #[tracing::instrument(skip(self))]
+    pub fn prune_rooms(&self) -> Result<usize> {
+        let mut removed = Vec::new();
+        for res in self.hash_map.iter() {
+            let (key, room_id) = res?;
+            if self.parent_child.contains_key(&key)? || self.child_parent.contains_key(&key)? {
+                continue;
+            }
+            // Checked again in the transaction as a link could have been added in the meantime
+            let pruned = (
+                &self.hash_map,
+                &self.state,
+                &self.parent_child,
+                &self.child_parent,
+            )
+                .transaction(
+                    |(hash_map, state, parent_child, child_parent)| -> TxResult<bool> {
+                        if parent_child.get(&key)?.is_some() || child_parent.get(&key)?.is_some() {
+                            return Ok(false);
+                        }
+                        hash_map.remove(&key)?;
+                        state.remove(&key)?;
+                        Ok(true)
+                    },
+                )?;
+            if !pruned {
+                continue;
+            }
+            self.room_name_cache
+                .write()
+                .unwrap()
+                .remove(std::str::from_utf8(room_id.as_ref()).unwrap_or_default());
+            removed.push(u128::from_le_bytes(GraphDb::fix_size(key.as_ref())));
+        }
+        self.update_snapshot(|snapshot| {
+            for hash in &removed {
+                snapshot.remove_node(*hash);
+            }
+        });
+
+        if !removed.is_empty() {
+            self.broadcast_removal(&removed, &[]);
+        }
+        Ok(removed.len())
+    }
+
+    /// Removes the link from parent_child, child_parent and its metadata
+    #[tracing::instrument(skip(self))]
+    fn unlink(&self, parent: u128, child: u128) -> Result<bool> {
+        let removed = (&self.parent_child, &self.child_parent, &self.edges).transaction(
+            |(parent_child, child_parent, edges)| -> TxResult<bool> {
+                let removed = GraphDb::remove_hash(parent_child, parent, child)?;
+                GraphDb::remove_hash(child_parent, child, parent)?;
+                edges.remove(&GraphDb::edge_key(parent, child)[..])?;
+                Ok(removed)
+            },
+        )?;
+        self.update_snapshot(|snapshot| snapshot.remove_link(parent, child));
+        Ok(removed)
+    }
+
+    /// Adds the hash to the list stored at key
+    #[tracing::instrument(skip(tree))]
+    fn push_hash(tree: &TransactionalTree, key: u128, hash: u128) -> TxResult {
+        let mut decoded: Vec<u128> = match tree.get(key.to_le_bytes())? {
+            Some(existing) => GraphDb::decode(&existing)?,
+            None => vec![],
+        };
+        if !decoded.contains(&hash) {
+            decoded.push(hash);
+            tree.insert(&key.to_le_bytes()[..], GraphDb::encode(&decoded)?)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the hash from the list stored at key. Empty lists get removed.
+    #[tracing::instrument(skip(tree))]
+    fn remove_hash(tree: &TransactionalTree, key: u128, hash: u128) -> TxResult<bool> {
+        let mut decoded: Vec<u128> = match tree.get(key.to_le_bytes())? {
+            Some(existing) => GraphDb::decode(&existing)?,
+            None => return Ok(false),
+        };
+        if !decoded.contains(&hash) {
+            return Ok(false);
+        }
+        decoded.retain(|x| *x != hash);
+        if decoded.is_empty() {
+            tree.remove(&key.to_le_bytes()[..])?;
+        } else {
+            tree.insert(&key.to_le_bytes()[..], GraphDb::encode(&decoded)?)?;
+        }
+        Ok(true)
+    }
+
+    /// Counts a mention of the link. The first mention creates the metadata.
+    #[tracing::instrument(skip(edges))]
+    fn count_mention(
+        edges: &TransactionalTree,
+        key: [u8; 32],
+        now: i64,
+        event_id: Option<&str>,
+    ) -> TxResult {
+        let meta = match edges
+            .get(key)?
+            .and_then(|x| bincode::deserialize::<EdgeMeta>(x.as_ref()).ok())
+        {
+            Some(mut meta) => {
+                meta.last_seen = now;
+                meta.mentions = meta.mentions.saturating_add(1);
+                meta
+            }
+            None => EdgeMeta {
+                first_seen: now,
+                last_seen: now,
+                mentions: 1,
+                event_id: event_id.map(ToString::to_string),
+            },
+        };
+        edges.insert(&key[..], GraphDb::encode(&meta)?)?;
+        Ok(())
+    }
+
+    fn encode<T: Serialize>(value: &T) -> TxResult<Vec<u8>> {
+        bincode::serialize(value).map_err(ConflictableTransactionError::Abort)
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> TxResult<T> {
+        bincode::deserialize(bytes).map_err(ConflictableTransactionError::Abort)
+    }
+
+    #[tracing::instrument(skip(tree))]
+    fn get_hashes(tree: &sled::Tree, key: u128) -> Vec<u128> {
+        match tree.get(key.to_le_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(bytes.as_ref()).unwrap_or_default(),
+            Ok(None) => vec![],
+            Err(e) => {
+                error!("Failed to get links: {}", e);
+                vec![]
+            }
+        }
     }
 
     #[tracing::instrument(skip(self))]
-    fn map_hash_to_room_id(&self, hash: u128, alias: &str) -> Result<()> {
-        self.hash_map.insert(hash.to_le_bytes(), alias.as_bytes())?;
-        self.hash_map.flush()?;
+    fn broadcast_removal(&self, rooms: &[u128], links: &[(u128, u128)]) {
+        let removed = RemovedJson {
+            removed_nodes: rooms
+                .iter()
+                .map(|hash| base64::encode(hash.to_le_bytes()))
+                .collect(),
+            removed_links: links
+                .iter()
+                .map(|(parent, child)| RemovedLink {
+                    source: base64::encode(parent.to_le_bytes()),
+                    target: base64::encode(child.to_le_bytes()),
+                })
+                .collect(),
+        };
+        if let Err(e) = self.websocket_tx.send(Some(WsMessage::Removed(removed))) {
+            error!("Failed to broadcast to websockets: {:?}", e);
+        }
+    }
+
+    /// Records that the child was mentioned in the parent
+    #[tracing::instrument(skip(self))]
+    pub fn record_mention(&self, parent: &str, child: &str, event_id: Option<&str>) -> Result<()> {
+        let now = Utc::now().timestamp_millis();
+        let key = GraphDb::edge_key(GraphDb::hash(parent), GraphDb::hash(child));
+        self.edges
+            .transaction(|edges| GraphDb::count_mention(edges, key, now, event_id))?;
+        self.snapshot_link(GraphDb::hash(parent), GraphDb::hash(child));
         Ok(())
     }
 
+    #[tracing::instrument(skip(self))]
+    fn get_edge(&self, parent: u128, child: u128) -> Option<EdgeMeta> {
+        match self.edges.get(GraphDb::edge_key(parent, child)) {
+            Ok(Some(bytes)) => bincode::deserialize(bytes.as_ref()).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to get edge: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Builds the api representation of a link. The value is the number of mentions.
+    #[tracing::instrument(skip(self))]
+    fn link(&self, parent: u128, child: u128) -> Link {
+        let meta = self.get_edge(parent, child);
+        Link {
+            source: base64::encode(parent.to_le_bytes()),
+            target: base64::encode(child.to_le_bytes()),
+            value: meta.as_ref().map(|x| x.mentions as i64).unwrap_or(1),
+            first_seen: meta.as_ref().map(|x| x.first_seen),
+            last_seen: meta.as_ref().map(|x| x.last_seen),
+        }
+    }
+
+    #[tracing::instrument]
+    fn edge_key(parent: u128, child: u128) -> [u8; 32] {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(&parent.to_le_bytes());
+        key[16..].copy_from_slice(&child.to_le_bytes());
+        key
+    }
+
+    #[tracing::instrument]
+    fn hash(input: &str) -> u128 {
+        xxhash_rust::xxh3::xxh3_128(input.as_bytes())
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn knows_room(&self, room_alias: &str) -> bool {
         let room_alias_hash = GraphDb::hash(room_alias);
@@ -290,6 +731,35 @@ impl GraphDb {
         r.values()
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_graph_stats(&self) -> GraphStats {
+        let rooms = self.hash_map.len() as i64;
+        let links = self
+            .get_all_parent_child()
+            .filter_map(|s| s.ok())
+            .map(|(_, val)| {
+                let child_hashes: Vec<u128> =
+                    bincode::deserialize(val.as_ref()).unwrap_or_default();
+                child_hashes.len() as i64
+            })
+            .sum();
+        let spaces = if let Some(client) = crate::MATRIX_CLIENT.get() {
+            client
+                .joined_rooms()
+                .iter()
+                .filter(|room| room.is_space() && self.knows_room(room.room_id().as_str()))
+                .count() as i64
+        } else {
+            0
+        };
+
+        GraphStats {
+            rooms,
+            links,
+            spaces,
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     fn get_room_id_from_hash(&self, hash: &u128) -> Option<IVec> {
         if let Ok(room_id) = self.hash_map.get(hash.to_le_bytes()) {
@@ -306,9 +776,7 @@ impl GraphDb {
     #[tracing::instrument(skip(self))]
     pub async fn get_node(&self, id: String) -> Option<RoomRelation> {
         let joined_members = self.get_synapse_joined_members_count().await;
-        if let Ok(hash) = base64::decode(id.clone()) {
-            let room_hash_bytes = GraphDb::fix_size(hash.as_ref());
-            let room_hash = u128::from_le_bytes(room_hash_bytes);
+        if let Some(room_hash) = GraphDb::decode_id(&id) {
             let room_id_bytes = self.get_room_id_from_hash(&room_hash);
             if let Some(room_id_bytes) = room_id_bytes {
                 let room_id = std::str::from_utf8(room_id_bytes.as_ref()).unwrap_or_default();
@@ -329,146 +797,277 @@ impl GraphDb {
         None
     }
 
+    /// Resolves a room ID or alias to the ID of a room in the graph
+    #[tracing::instrument(skip(self))]
+    pub async fn resolve_room(&self, room_id_or_alias: &str) -> Option<String> {
+        if room_id_or_alias.starts_with('!') {
+            if self.knows_room(room_id_or_alias) {
+                return Some(room_id_or_alias.to_string());
+            }
+            return None;
+        }
+
+        let alias = RoomAliasId::try_from(room_id_or_alias).ok()?;
+        let client = crate::MATRIX_CLIENT.get()?;
+        let room_id = match client.joined_rooms().iter().find(|room| {
+            room.canonical_alias().as_ref() == Some(&alias) || room.alt_aliases().contains(&alias)
+        }) {
+            Some(room) => room.room_id().to_string(),
+            None => match client.send(GetAliasRequest::new(&alias), None).await {
+                Ok(resp) => resp.room_id.to_string(),
+                Err(e) => {
+                    error!("Failed to resolve alias {}: {}", alias, e);
+                    return None;
+                }
+            },
+        };
+        if self.knows_room(&room_id) {
+            return Some(room_id);
+        }
+        None
+    }
+
+    /// Returns a room with its parents and children
+    #[tracing::instrument(skip(self))]
+    pub async fn get_room(&self, room_id: &str) -> Option<RoomJson> {
+        let hash = GraphDb::hash(room_id);
+        let id = base64::encode(hash.to_le_bytes());
+        let mut node = self.get_node(id).await?;
+
+        let neighbour = |other: u128, link: Link| -> Option<NeighbourJson> {
+            let room_id = self.get_room_id_from_hash(&other)?;
+            Some(NeighbourJson {
+                id: base64::encode(other.to_le_bytes()),
+                room_id: std::str::from_utf8(room_id.as_ref())
+                    .unwrap_or_default()
+                    .to_string(),
+                link,
+            })
+        };
+        let parents: Vec<NeighbourJson> = GraphDb::get_hashes(&self.child_parent, hash)
+            .into_iter()
+            .filter_map(|parent| neighbour(parent, self.link(parent, hash)))
+            .collect();
+        let children: Vec<NeighbourJson> = GraphDb::get_hashes(&self.parent_child, hash)
+            .into_iter()
+            .filter_map(|child| neighbour(child, self.link(hash, child)))
+            .collect();
+
+        node.incoming_links = Some(parents.len().try_into().unwrap());
+        node.outgoing_links = Some(children.len().try_into().unwrap());
+        node.weight = Some((parents.len() + children.len()).try_into().unwrap());
+        Some(RoomJson {
+            node,
+            parents,
+            children,
+        })
+    }
+
+    /// Decodes the base64 id used by the api. Returns None for malformed ids.
+    #[tracing::instrument]
+    fn decode_id(id: &str) -> Option<u128> {
+        let bytes = base64::decode(id).ok()?;
+        let bytes: [u8; 16] = bytes.as_slice().try_into().ok()?;
+        Some(u128::from_le_bytes(bytes))
+    }
+
     #[tracing::instrument]
     fn fix_size(raw: &[u8]) -> [u8; 16] {
         raw.try_into().expect("slice with incorrect length")
     }
 
+    /// Returns the relations snapshot. The first call builds it.
     #[tracing::instrument(skip(self))]
-    pub async fn get_json_relations(&self) -> RelationsJson {
-        let mut nodes = BTreeSet::new();
-        let mut all_links = BTreeSet::new();
-
-        let room_id_relations: RelationsMix = self
-            .get_all_parent_child()
-            .filter_map(|s| s.ok())
-            .map(|(key, val)| {
-                let parent_hash = GraphDb::fix_size(key.as_ref());
-                let child_hashes: BTreeSet<u128> = bincode::deserialize(val.as_ref()).unwrap();
-                let parent_hash = u128::from_le_bytes(parent_hash);
+    pub async fn get_json_relations(&self) -> Arc<RelationsJson> {
+        let json = {
+            self.relations
+                .write()
+                .unwrap()
+                .as_mut()
+                .map(RelationsSnapshot::json)
+        };
+        if let Some(json) = json {
+            return json;
+        }
 
-                let parent = self.get_room_id_from_hash(&parent_hash);
-                let parent_hash = base64::encode(parent_hash.to_le_bytes());
-                ((parent_hash, parent), child_hashes)
-            })
-            .map(|((parent_hash, parent_bytes), child_hashes)| {
-                let mut parent = "".to_string();
-                if let Some(parent_bytes) = parent_bytes {
-                    parent = std::str::from_utf8(parent_bytes.as_ref())
-                        .unwrap_or_default()
-                        .to_string();
-                }
+        self.rebuild_relations().await;
+        self.relations
+            .write()
+            .unwrap()
+            .get_or_insert_with(RelationsSnapshot::default)
+            .json()
+    }
 
-                ((parent_hash, parent), child_hashes)
-            })
-            .collect();
+    /// Builds the relations snapshot from sled.
+    ///
+    /// Names and member counts of rooms only get updated by this or when a room gets refreshed.
+    #[tracing::instrument(skip(self))]
+    pub async fn rebuild_relations(&self) {
+        let client = if let Some(client) = crate::MATRIX_CLIENT.get() {
+            client
+        } else {
+            panic!("SDK Client missing");
+        };
+        let started = {
+            self.relations
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(RelationsSnapshot::generation)
+        };
 
+        let joined_rooms = client.joined_rooms();
         let joined_members = self.get_synapse_joined_members_count().await;
-        if let Some(client) = crate::MATRIX_CLIENT.get() {
-            let joined_rooms = client.joined_rooms();
-            for ((parent_hash, parent), child_hashes) in room_id_relations {
-                if let Some(relation) = self
-                    .generate_room_relation(
-                        parent_hash.clone(),
-                        &parent,
-                        &joined_rooms,
-                        &joined_members,
-                    )
+        let mut rooms = BTreeSet::new();
+        for (parent, children) in self.get_all_parent_child().filter_map(|s| s.ok()) {
+            let children: Vec<u128> = bincode::deserialize(children.as_ref()).unwrap_or_default();
+            rooms.insert(u128::from_le_bytes(GraphDb::fix_size(parent.as_ref())));
+            rooms.extend(children);
+        }
+        let mut nodes = BTreeMap::new();
+        for hash in rooms {
+            if let Some(room_id) = self.get_room_id_from_hash(&hash) {
+                let room_id = std::str::from_utf8(room_id.as_ref()).unwrap_or_default();
+                if let Some(node) = self
+                    .generate_graph_node(hash, room_id, &joined_rooms, &joined_members)
                     .await
                 {
-                    let links: BTreeSet<Link> = child_hashes
-                        .iter()
-                        .map(|child_hash| base64::encode(child_hash.to_le_bytes()))
-                        .map(|child| Link {
-                            source: parent_hash.clone(),
-                            target: child,
-                            value: 1,
-                        })
-                        .collect();
-
-                    if relation.name == "MTRNord" || parent_hash == "4u98GV1CGlCn6PvxBerjrw==" {
-                        continue;
-                    }
-                    all_links.extend(links.into_iter());
-
-                    // TODO Use tokio channel to allow streaming of nodes
-                    // Add the parent
-                    nodes.insert(relation);
+                    nodes.insert(hash, node);
                 }
             }
+        }
 
-            let missing_child_nodes_links: Vec<&Link> = all_links
-                .iter()
-                .filter(|link| {
-                    !nodes
-                        .iter()
-                        .any(|relation: &RoomRelation| relation.id == link.target)
-                })
-                .collect();
+        // Links are read after the nodes to not miss links written in the meantime
+        let links = self.get_all_links();
+        let mut relations = self.relations.write().unwrap();
+        let snapshot = relations.get_or_insert_with(RelationsSnapshot::default);
+        if started.is_some() && started != Some(snapshot.generation()) {
+            // Keep rooms which were added while this was running
+            for (hash, node) in snapshot.nodes() {
+                if !nodes.contains_key(hash)
+                    && links
+                        .keys()
+                        .any(|(parent, child)| parent == hash || child == hash)
+                {
+                    nodes.insert(*hash, node.clone());
+                }
+            }
+        }
+        snapshot.replace(nodes, links);
+    }
 
-            // Add missing childs
-            for link in &missing_child_nodes_links {
-                if let Ok(hash) = base64::decode(link.target.clone()) {
-                    let room_hash_bytes = GraphDb::fix_size(hash.as_ref());
-                    let room_hash = u128::from_le_bytes(room_hash_bytes);
-                    let room_id_bytes = self.get_room_id_from_hash(&room_hash);
-                    if let Some(room_id_bytes) = room_id_bytes {
-                        let room_id =
-                            std::str::from_utf8(room_id_bytes.as_ref()).unwrap_or_default();
-                        if let Some(relation) = self
-                            .generate_room_relation(
-                                link.target.clone(),
-                                room_id,
-                                &joined_rooms,
-                                &joined_members,
-                            )
-                            .await
-                        {
-                            if relation.name == "MTRNord"
-                                || link.target == "4u98GV1CGlCn6PvxBerjrw=="
-                            {
-                                continue;
-                            }
-                            nodes.insert(relation);
-                        }
-                    }
+    /// Picks up changed state of a room and sends the new node to the websocket clients
+    #[tracing::instrument(skip(self))]
+    pub async fn update_room(&self, room_id: &str) {
+        if !self.knows_room(room_id) {
+            return;
+        }
+        self.room_name_cache.write().unwrap().remove(room_id);
+        if let Some(client) = crate::MATRIX_CLIENT.get() {
+            let joined_rooms = client.joined_rooms();
+            // The state events don't change the member count so the stored one is used
+            let node = self
+                .refresh_node(
+                    GraphDb::hash(room_id),
+                    room_id,
+                    &joined_rooms,
+                    &BTreeMap::new(),
+                )
+                .await;
+            if let Some(node) = node {
+                let updated = UpdatedJson { updated_node: node };
+                if let Err(e) = self.websocket_tx.send(Some(WsMessage::Updated(updated))) {
+                    error!("Failed to broadcast to websockets: {:?}", e);
                 }
             }
+        }
+    }
 
-            // Remove broken links
-            let node_ids: BTreeSet<String> = nodes.iter().map(|node| node.id.clone()).collect();
-            all_links.retain(|link| {
-                node_ids.contains(&link.target.to_string())
-                    && node_ids.contains(&link.source.to_string())
-            });
+    /// Generates the node of the room and puts it into the relations snapshot.
+    ///
+    /// Rooms which are not shown get removed from the snapshot.
+    #[tracing::instrument(skip(self, joined_rooms, joined_members))]
+    async fn refresh_node(
+        &self,
+        hash: u128,
+        room_id: &str,
+        joined_rooms: &[Joined],
+        joined_members: &BTreeMap<String, i64>,
+    ) -> Option<RoomRelation> {
+        let node = self
+            .generate_graph_node(hash, room_id, joined_rooms, joined_members)
+            .await;
+        self.update_snapshot(|snapshot| match &node {
+            Some(node) => snapshot.set_node(hash, node.clone()),
+            None => snapshot.remove_node(hash),
+        });
+        node
+    }
 
-            let nodes: BTreeSet<RoomRelation> = nodes
-                .into_iter()
-                .map(|mut node| {
-                    let links = all_links
-                        .iter()
-                        .filter(|x| {
-                            (x.source == node.id || x.target == node.id) && x.source != x.target
-                        })
-                        .count();
-                    let incoming_links = all_links.iter().filter(|x| x.target == node.id).count();
-                    let outgoing_links = all_links.iter().filter(|x| x.source == node.id).count();
-                    node.weight = Some(links.try_into().unwrap());
-                    node.incoming_links = Some(incoming_links.try_into().unwrap());
-                    node.outgoing_links = Some(outgoing_links.try_into().unwrap());
-                    node
-                })
-                .collect();
+    /// Like generate_room_relation but None for rooms which are hidden from the graph
+    #[tracing::instrument(skip(self, joined_rooms, joined_members))]
+    async fn generate_graph_node(
+        &self,
+        hash: u128,
+        room_id: &str,
+        joined_rooms: &[Joined],
+        joined_members: &BTreeMap<String, i64>,
+    ) -> Option<RoomRelation> {
+        let id = base64::encode(hash.to_le_bytes());
+        if id == "4u98GV1CGlCn6PvxBerjrw==" {
+            return None;
+        }
+        let relation = self
+            .generate_room_relation(id, room_id, joined_rooms, joined_members)
+            .await?;
+        if relation.name == "MTRNord" {
+            return None;
+        }
+        Some(relation)
+    }
 
-            RelationsJson {
-                nodes,
-                links: all_links,
+    /// Applies a change to the relations snapshot. Does nothing before it was built.
+    #[tracing::instrument(skip(self, change))]
+    fn update_snapshot(&self, change: impl FnOnce(&mut RelationsSnapshot)) {
+        if let Some(snapshot) = self.relations.write().unwrap().as_mut() {
+            change(snapshot);
+        }
+    }
+
+    /// Puts the current state of the link into the relations snapshot
+    #[tracing::instrument(skip(self))]
+    fn snapshot_link(&self, parent: u128, child: u128) {
+        if self.relations.read().unwrap().is_none() {
+            return;
+        }
+        let link = self.link(parent, child);
+        self.update_snapshot(|snapshot| snapshot.set_link(parent, child, link));
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn snapshot_misses_node(&self, hash: u128) -> bool {
+        match self.relations.read().unwrap().as_ref() {
+            Some(snapshot) => !snapshot.has_node(hash),
+            None => false,
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn get_all_links(&self) -> BTreeMap<(u128, u128), Link> {
+        let mut links = BTreeMap::new();
+        for (parent, children) in self.get_all_parent_child().filter_map(|s| s.ok()) {
+            let parent = u128::from_le_bytes(GraphDb::fix_size(parent.as_ref()));
+            let children: Vec<u128> = bincode::deserialize(children.as_ref()).unwrap_or_default();
+            for child in children {
+                links.insert((parent, child), self.link(parent, child));
             }
-        } else {
-            panic!("SDK Client missing");
         }
+        links
     }
 
+    /// Builds the node of a room from the joined room and stores its details.
+    ///
+    /// Rooms which were left or are missing from the SDK store use the last stored details.
     #[tracing::instrument(skip(self, joined_rooms, joined_members))]
     async fn generate_room_relation(
         &self,
@@ -479,11 +1078,19 @@ impl GraphDb {
     ) -> Option<RoomRelation> {
         let room_id_serialized = &RoomId::try_from(room_id).unwrap();
 
-        if let Some(room) = joined_rooms
+        let meta = if let Some(room) = joined_rooms
             .iter()
             .find(|room| room.room_id() == room_id_serialized)
         {
             if room.is_tombstoned() {
+                if let Some(mut meta) = self.get_room_meta(room_id) {
+                    meta.tombstoned = true;
+                    // Keeps check_topic of the appservice from updating the room on every event
+                    meta.topic = room.topic().unwrap_or_default();
+                    if let Err(e) = self.set_room_meta(room_id, meta) {
+                        error!("Failed to save room metadata: {}", e);
+                    }
+                }
                 return None;
             }
             let alias = if let Some(alias) = room.canonical_alias() {
@@ -492,7 +1099,7 @@ impl GraphDb {
                 room_id.into()
             };
 
-            // TODO update on changes somehow
+            // Gets cleared by update_room when the name or alias changes
             let mut name = { self.room_name_cache.read().unwrap().get(room_id).cloned() };
             if name.is_none() {
                 name = if let Ok(name) = room.display_name().await {
@@ -524,24 +1131,76 @@ impl GraphDb {
             let members = if let Some(members) = joined_members.get(room_id) {
                 *members
             } else {
-                0
+                self.get_room_meta(room_id).map(|x| x.members).unwrap_or(0)
             };
-            return Some(RoomRelation {
-                id: room_hash,
+            let meta = RoomMeta {
                 name,
                 alias,
                 avatar: avatar_url,
                 topic,
-                weight: None,
-                incoming_links: None,
-                outgoing_links: None,
-                room_id: room_id.into(),
                 is_space: room.is_space(),
                 members,
-            });
+                join_rule: room.join_rule().to_string(),
+                tombstoned: false,
+                updated: Utc::now().timestamp_millis(),
+            };
+            if let Err(e) = self.set_room_meta(room_id, meta.clone()) {
+                error!("Failed to save room metadata: {}", e);
+            }
+            meta
+        } else {
+            let mut meta = self.get_room_meta(room_id)?;
+            if meta.tombstoned {
+                return None;
+            }
+            if let Some(members) = joined_members.get(room_id) {
+                meta.members = *members;
+            }
+            meta
+        };
+
+        Some(RoomRelation {
+            id: room_hash,
+            name: meta.name,
+            alias: meta.alias,
+            avatar: meta.avatar,
+            topic: meta.topic,
+            weight: None,
+            incoming_links: None,
+            outgoing_links: None,
+            room_id: room_id.into(),
+            is_space: meta.is_space,
+            members: meta.members,
+            join_rule: meta.join_rule,
+        })
+    }
+
+    /// Returns the last known details of the room
+    #[tracing::instrument(skip(self))]
+    pub fn get_room_meta(&self, room_id: &str) -> Option<RoomMeta> {
+        match self.state.get(GraphDb::hash(room_id).to_le_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(bytes.as_ref()).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to get room metadata: {}", e);
+                None
+            }
         }
+    }
 
-        None
+    /// Stores the details of the room. Nothing gets written if only the timestamp changed.
+    #[tracing::instrument(skip(self))]
+    fn set_room_meta(&self, room_id: &str, meta: RoomMeta) -> Result<()> {
+        if let Some(existing) = self.get_room_meta(room_id) {
+            if existing.same_details(&meta) {
+                return Ok(());
+            }
+        }
+        self.state.insert(
+            GraphDb::hash(room_id).to_le_bytes(),
+            bincode::serialize(&meta)?,
+        )?;
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, joined_rooms))]
@@ -562,11 +1221,10 @@ impl GraphDb {
             .filter_map(|s| s.ok())
             .map(|(key, val)| {
                 let parent_hash = GraphDb::fix_size(key.as_ref());
-                let child_hashes: BTreeSet<u128> = bincode::deserialize(val.as_ref()).unwrap();
+                let child_hashes: Vec<u128> = bincode::deserialize(val.as_ref()).unwrap();
                 let parent_hash = u128::from_le_bytes(parent_hash);
 
                 let parent = self.get_room_id_from_hash(&parent_hash);
-                let parent_hash = base64::encode(parent_hash.to_le_bytes());
                 ((parent_hash, parent), child_hashes)
             })
             .map(|((parent_hash, parent_bytes), child_hashes)| {
@@ -583,16 +1241,12 @@ impl GraphDb {
 
         if let Some(client) = crate::MATRIX_CLIENT.get() {
             let joined_rooms = client.joined_rooms();
-            for ((parent_hash, parent), child_hashes) in room_id_relations {
+            for ((parent_hash_raw, parent), child_hashes) in room_id_relations {
+                let parent_hash = base64::encode(parent_hash_raw.to_le_bytes());
                 if self.is_joined(&parent, &joined_rooms).await {
                     let links: BTreeSet<Link> = child_hashes
                         .iter()
-                        .map(|child_hash| base64::encode(child_hash.to_le_bytes()))
-                        .map(|child| Link {
-                            source: parent_hash.clone(),
-                            target: child,
-                            value: 1,
-                        })
+                        .map(|child_hash| self.link(parent_hash_raw, *child_hash))
                         .collect();
 
                     if parent_hash == "4u98GV1CGlCn6PvxBerjrw==" {
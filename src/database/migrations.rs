@@ -0,0 +1,114 @@
+use crate::{errors::Errors, matrix::MatrixSsServername};
+use color_eyre::Result;
+use tracing::info;
+
+/// Version of the layout this build reads and writes
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// Stored in the default tree. Databases without it have version 0.
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Brings the database from version - 1 to version
+pub struct Migration {
+    pub version: u64,
+    pub description: &'static str,
+    run: fn(&sled::Db) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Remove duplicate and empty relation lists",
+        run: dedup_relations,
+    },
+    Migration {
+        version: 2,
+        description: "Remove server addresses stored as plain strings",
+        run: remove_legacy_addresses,
+    },
+];
+
+#[tracing::instrument(skip(db))]
+pub fn get_version(db: &sled::Db) -> Result<u64> {
+    match db.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => Ok(bincode::deserialize(bytes.as_ref())?),
+        None => Ok(0),
+    }
+}
+
+#[tracing::instrument(skip(db))]
+fn set_version(db: &sled::Db, version: u64) -> Result<()> {
+    db.insert(SCHEMA_VERSION_KEY, bincode::serialize(&version)?)?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Returns the migrations which did not run yet.
+///
+/// Fails for databases written by a newer build.
+#[tracing::instrument(skip(db))]
+pub fn pending(db: &sled::Db) -> Result<Vec<&'static Migration>> {
+    let version = get_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(Errors::SchemaVersionTooNew(version, SCHEMA_VERSION).into());
+    }
+    Ok(MIGRATIONS.iter().filter(|x| x.version > version).collect())
+}
+
+/// Runs all pending migrations in order. The version gets saved after each of them.
+///
+/// Returns the number of migrations which ran.
+#[tracing::instrument(skip(db))]
+pub fn migrate(db: &sled::Db) -> Result<usize> {
+    let pending = pending(db)?;
+    for migration in &pending {
+        info!(
+            "Migrating cache to schema version {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)(db)?;
+        set_version(db, migration.version)?;
+    }
+    Ok(pending.len())
+}
+
+/// The relation lists are read as sets of links so they must not contain duplicates
+#[tracing::instrument(skip(db))]
+fn dedup_relations(db: &sled::Db) -> Result<()> {
+    for name in [&b"parent_child"[..], &b"child_parent"[..]].iter() {
+        let tree = db.open_tree(name)?;
+        for res in tree.iter() {
+            let (key, value) = res?;
+            let hashes: Vec<u128> = bincode::deserialize(value.as_ref())?;
+            let mut deduped: Vec<u128> = Vec::with_capacity(hashes.len());
+            for hash in &hashes {
+                if !deduped.contains(hash) {
+                    deduped.push(*hash);
+                }
+            }
+
+            if deduped.is_empty() {
+                tree.remove(key)?;
+            } else if deduped.len() != hashes.len() {
+                tree.insert(key, bincode::serialize(&deduped)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Addresses used to be stored as plain strings. Those can't be decoded anymore which would
+/// leave the server known but never polled, so they get removed and resolved again.
+#[tracing::instrument(skip(db))]
+fn remove_legacy_addresses(db: &sled::Db) -> Result<()> {
+    let mut removed = 0;
+    for res in db.scan_prefix(b"address/") {
+        let (key, value) = res?;
+        if bincode::deserialize::<MatrixSsServername>(value.as_ref()).is_err() {
+            db.remove(key)?;
+            removed += 1;
+        }
+    }
+    info!("Removed {} legacy addresses", removed);
+    Ok(())
+}
@@ -1,2 +1,5 @@
+pub mod aliases;
 pub mod cache;
 pub mod graph;
+pub mod migrations;
+pub mod snapshot;
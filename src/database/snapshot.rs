@@ -0,0 +1,129 @@
+use crate::webpage::api::{Link, RelationsJson, RoomRelation};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    sync::Arc,
+};
+
+/// The graph as it is served by `/relations`.
+///
+/// It gets updated by the writes of the GraphDb instead of walking sled on every request.
+/// Every change increases the generation.
+#[derive(Debug, Default)]
+pub struct RelationsSnapshot {
+    generation: u64,
+    /// Rooms which are shown in the graph
+    nodes: BTreeMap<u128, RoomRelation>,
+    /// All links. Links to rooms which are not shown get filtered when building the json
+    links: BTreeMap<(u128, u128), Link>,
+    /// Json of the current generation. Built on the first request after a change
+    json: Option<Arc<RelationsJson>>,
+}
+
+impl RelationsSnapshot {
+    /// Replaces the content while keeping the generation counting up
+    pub fn replace(
+        &mut self,
+        nodes: BTreeMap<u128, RoomRelation>,
+        links: BTreeMap<(u128, u128), Link>,
+    ) {
+        self.nodes = nodes;
+        self.links = links;
+        self.changed();
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<u128, RoomRelation> {
+        &self.nodes
+    }
+
+    pub fn has_node(&self, hash: u128) -> bool {
+        self.nodes.contains_key(&hash)
+    }
+
+    pub fn set_node(&mut self, hash: u128, node: RoomRelation) {
+        if self.nodes.get(&hash) != Some(&node) {
+            self.nodes.insert(hash, node);
+            self.changed();
+        }
+    }
+
+    pub fn remove_node(&mut self, hash: u128) {
+        if self.nodes.remove(&hash).is_some() {
+            self.changed();
+        }
+    }
+
+    pub fn set_link(&mut self, parent: u128, child: u128, link: Link) {
+        self.links.insert((parent, child), link);
+        self.changed();
+    }
+
+    pub fn remove_link(&mut self, parent: u128, child: u128) {
+        if self.links.remove(&(parent, child)).is_some() {
+            self.changed();
+        }
+    }
+
+    /// Returns the json of the current generation
+    pub fn json(&mut self) -> Arc<RelationsJson> {
+        if let Some(json) = &self.json {
+            return json.clone();
+        }
+        let json = Arc::new(self.build_json());
+        self.json = Some(json.clone());
+        json
+    }
+
+    fn changed(&mut self) {
+        self.generation += 1;
+        self.json = None;
+    }
+
+    fn build_json(&self) -> RelationsJson {
+        let links: BTreeSet<Link> = self
+            .links
+            .iter()
+            .filter(|((parent, child), _)| {
+                self.nodes.contains_key(parent) && self.nodes.contains_key(child)
+            })
+            .map(|(_, link)| link.clone())
+            .collect();
+
+        let mut incoming: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut outgoing: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut weight: BTreeMap<&str, usize> = BTreeMap::new();
+        for link in &links {
+            *incoming.entry(link.target.as_str()).or_default() += 1;
+            *outgoing.entry(link.source.as_str()).or_default() += 1;
+            if link.source != link.target {
+                *weight.entry(link.source.as_str()).or_default() += 1;
+                *weight.entry(link.target.as_str()).or_default() += 1;
+            }
+        }
+
+        let count = |map: &BTreeMap<&str, usize>, id: &str| -> Option<i32> {
+            Some(map.get(id).copied().unwrap_or(0).try_into().unwrap())
+        };
+        let nodes = self
+            .nodes
+            .values()
+            .map(|node| {
+                let mut node = node.clone();
+                node.weight = count(&weight, &node.id);
+                node.incoming_links = count(&incoming, &node.id);
+                node.outgoing_links = count(&outgoing, &node.id);
+                node
+            })
+            .collect();
+
+        RelationsJson {
+            generation: self.generation,
+            nodes,
+            links,
+        }
+    }
+}
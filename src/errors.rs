@@ -11,8 +11,20 @@ pub enum Errors {
     DatabaseError(String),
     #[error("Error when talking to influxdb: '{0}'")]
     InfluxDbError(String),
+    #[error("Storage path '{0}' is not usable: {1}")]
+    StorageError(String, String),
+    #[error("The cache has schema version {0} but this build only supports up to {1}")]
+    SchemaVersionTooNew(u64, u64),
+    #[error("The metrics sink name '{0}' is empty, contains a '/' or is used more than once")]
+    InvalidMetricsSinkName(String),
+    #[error("Unable to set up the dns resolver: '{0}'")]
+    DnsResolverError(String),
     #[error("You Matrix Server is not configured correctly")]
     MatrixFederationWronglyConfigured,
+    #[error("You Matrix Server has federation explicitly disabled")]
+    MatrixFederationUnavailable,
     #[error("You Matrix Server is not reporting a version")]
     MatrixFederationVersionWronglyConfigured,
+    #[error("You Matrix Server is not reporting valid signing keys")]
+    MatrixFederationKeysWronglyConfigured,
 }
@@ -1,10 +1,15 @@
-use std::time::Duration;
-
 use futures::stream::{self, StreamExt};
+use prometheus::IntGaugeVec;
 use sqlx::PgPool;
 use tracing::{error, info};
 
-use crate::{config::Config, database::cache::CacheDb, scraping::InfluxDb};
+use crate::{
+    config::Config,
+    database::cache::CacheDb,
+    matrix::ServerImplementation,
+    scraping::Metrics,
+    webpage::metrics::{JOB_DURATION, SERVERS},
+};
 
 pub async fn find_servers(
     pool: &PgPool,
@@ -12,23 +17,37 @@ pub async fn find_servers(
     config: &Config,
 ) -> color_eyre::Result<()> {
     info!("Started find_servers task");
+    let _timer = JOB_DURATION
+        .with_label_values(&["find_servers"])
+        .start_timer();
 
     crate::matrix::fetch_servers_from_db(pool, config, cache).await?;
     info!("Finished find_servers task");
     Ok(())
 }
 
-pub async fn update_versions(cache: &CacheDb, influx_db: InfluxDb) -> color_eyre::Result<()> {
+pub async fn refresh_servers(cache: &CacheDb, config: &Config) -> color_eyre::Result<()> {
+    info!("Started refresh_servers task");
+
+    crate::matrix::refresh_server_addresses(config, cache).await?;
+    info!("Finished refresh_servers task");
+    Ok(())
+}
+
+pub async fn update_versions(
+    cache: &CacheDb,
+    metrics: Metrics,
+    config: &Config,
+    outdated_gauge: &IntGaugeVec,
+) -> color_eyre::Result<()> {
     info!("Started update_versions task");
+    let _timer = JOB_DURATION
+        .with_label_values(&["update_versions"])
+        .start_timer();
 
     let servers = cache.get_all_servers();
     let stream = stream::iter(servers);
 
-    let client = reqwest::ClientBuilder::new()
-        .timeout(Duration::from_secs(30))
-        .user_agent(crate::APP_USER_AGENT)
-        .build()?;
-
     stream
         .map(|val| async move {
             let server_address_bytes = val.expect("unable to get server_address from sled");
@@ -38,16 +57,130 @@ pub async fn update_versions(cache: &CacheDb, influx_db: InfluxDb) -> color_eyre
             server_address
         })
         .for_each_concurrent(None, |server_address| async {
-            if let Err(e) =
-                crate::matrix::fetch_server_version(&server_address.await, &client, &cache.clone())
-                    .await
+            let server_address = server_address.await;
+            match cache.get_reachability(&server_address) {
+                Ok(Some(reachability)) if !reachability.should_poll(&config.polling) => return,
+                Err(e) => error!("Failed to get reachability: {}", e),
+                _ => {}
+            }
+            if let Err(e) = crate::matrix::fetch_server_version(
+                &server_address,
+                &cache.clone(),
+                &config.polling,
+            )
+            .await
             {
                 error!("Failed to get version: {}", e);
             };
         })
         .await;
+
+    info!("Checking versions against advisories");
+    let flagged = crate::matrix::flag_outdated_servers(&config.advisories, cache)?;
+    outdated_gauge.reset();
+    for flagged_server in flagged {
+        outdated_gauge
+            .with_label_values(&[
+                &flagged_server.server_name,
+                flagged_server.implementation.as_str(),
+            ])
+            .set(1);
+    }
+
+    count_servers(cache);
+
     info!("Pushing updated versions");
-    influx_db.push_versions(cache).await?;
+    metrics.push_versions(cache).await?;
     info!("Finished update_versions task");
     Ok(())
 }
+
+/// Updates the gauge of servers by implementation and reachability
+fn count_servers(cache: &CacheDb) {
+    SERVERS.reset();
+    cache
+        .get_all_servers()
+        .filter_map(|x| x.ok())
+        .map(|server_name_bytes| {
+            String::from_utf8_lossy(server_name_bytes.as_ref()).replace("address/", "")
+        })
+        .for_each(|server_name| {
+            let implementation = match cache.get_server_version(&server_name) {
+                Ok(Some(version)) => ServerImplementation::from_name(&version.name).as_str(),
+                _ => "unknown",
+            };
+            let reachability = match cache.get_reachability(&server_name) {
+                Ok(Some(reachability)) if reachability.dead => "dead",
+                Ok(Some(reachability)) if reachability.consecutive_failures > 0 => "unreachable",
+                Ok(Some(_)) => "reachable",
+                _ => "unknown",
+            };
+            SERVERS
+                .with_label_values(&[implementation, reachability])
+                .inc();
+        });
+}
+
+pub async fn replay_metrics(metrics: Metrics) -> color_eyre::Result<()> {
+    metrics.replay_spool().await
+}
+
+pub async fn update_keys(cache: &CacheDb, metrics: Metrics) -> color_eyre::Result<()> {
+    info!("Started update_keys task");
+
+    let servers: Vec<String> = cache
+        .get_all_servers()
+        .filter_map(|x| x.ok())
+        .map(|server_name_bytes| {
+            String::from_utf8_lossy(server_name_bytes.as_ref()).replace("address/", "")
+        })
+        .collect();
+
+    stream::iter(servers)
+        .for_each_concurrent(None, |server_name| async move {
+            if let Err(e) = crate::matrix::fetch_server_keys(&server_name, cache).await {
+                error!("Failed to get keys: {}", e);
+            };
+        })
+        .await;
+    info!("Pushing updated keys");
+    metrics.push_keys(cache).await?;
+    info!("Finished update_keys task");
+    Ok(())
+}
+
+pub async fn update_graph_stats(cache: &CacheDb, metrics: Metrics) -> color_eyre::Result<()> {
+    info!("Started update_graph_stats task");
+    metrics.push_graph_stats(cache).await?;
+    info!("Finished update_graph_stats task");
+    Ok(())
+}
+
+/// Rebuilds the relations snapshot to pick up names and member counts which changed
+pub async fn refresh_relations(cache: &CacheDb) -> color_eyre::Result<()> {
+    info!("Started refresh_relations task");
+    cache.graph.rebuild_relations().await;
+    info!("Finished refresh_relations task");
+    Ok(())
+}
+
+pub async fn update_reachability(cache: &CacheDb, metrics: Metrics) -> color_eyre::Result<()> {
+    info!("Started update_reachability task");
+    metrics.push_reachability(cache).await?;
+    info!("Finished update_reachability task");
+    Ok(())
+}
+
+pub async fn update_room_members(cache: &CacheDb, metrics: Metrics) -> color_eyre::Result<()> {
+    info!("Started update_room_members task");
+    metrics.push_room_members(cache).await?;
+    info!("Finished update_room_members task");
+    Ok(())
+}
+
+pub async fn update_crawl_activity(cache: &CacheDb, metrics: Metrics) -> color_eyre::Result<()> {
+    info!("Started update_crawl_activity task");
+    metrics.push_crawl_activity(cache).await?;
+    info!("Finished update_crawl_activity task");
+    Ok(())
+}
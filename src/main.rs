@@ -3,8 +3,8 @@
 use crate::{
     config::Config,
     database::cache::CacheDb,
-    scraping::InfluxDb,
-    webpage::{init_prometheus, run_server},
+    scraping::Metrics,
+    webpage::{api::WsMessage, init_prometheus, run_server},
 };
 use chrono::{prelude::*, Duration};
 use clap::Clap;
@@ -16,7 +16,10 @@ use matrix_sdk::{
 };
 use once_cell::sync::{Lazy, OnceCell};
 use opentelemetry::{global, trace::TracerProvider};
-use prometheus::core::{AtomicF64, GenericGauge};
+use prometheus::{
+    core::{AtomicF64, GenericGauge},
+    IntGaugeVec,
+};
 use sqlx::{postgres::PgPoolOptions, PgPool};
 use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
 use tokio::sync::{watch, Semaphore};
@@ -39,6 +42,108 @@ mod webpage;
 struct Opts {
     #[clap(short, long, default_value = "./config.yml")]
     config: String,
+    #[clap(subcommand)]
+    subcommand: Option<SubCommand>,
+}
+
+/// Work on the cache without starting the bot. The bot must not run at the same time.
+#[derive(Clap)]
+enum SubCommand {
+    /// Shows the schema version of the cache and the pending migrations
+    Schema,
+    /// Applies the pending migrations of the cache
+    Migrate,
+    /// Removes a room with all of its links. It does not get added again until it is unblocked.
+    RemoveRoom {
+        /// Room ID of the room
+        room_id: String,
+    },
+    /// Allows a removed room to be added again
+    UnblockRoom {
+        /// Room ID of the room
+        room_id: String,
+    },
+    /// Removes the link between two rooms
+    RemoveLink {
+        /// Room ID of the room the link was found in
+        parent: String,
+        /// Room ID of the linked room
+        child: String,
+    },
+    /// Removes rooms without any links
+    PruneRooms,
+}
+
+#[tracing::instrument(skip(subcommand))]
+fn run_subcommand(subcommand: SubCommand, config: &Config) -> Result<()> {
+    match subcommand {
+        SubCommand::Schema => {
+            let db = CacheDb::open(&config.storage)?;
+            println!(
+                "Schema version: {} (this build: {})",
+                database::migrations::get_version(&db)?,
+                database::migrations::SCHEMA_VERSION
+            );
+            for migration in database::migrations::pending(&db)? {
+                println!("Pending {}: {}", migration.version, migration.description);
+            }
+        }
+        SubCommand::Migrate => {
+            let db = CacheDb::open(&config.storage)?;
+            let applied = database::migrations::migrate(&db)?;
+            println!(
+                "Applied {} migrations. Schema version: {}",
+                applied,
+                database::migrations::get_version(&db)?
+            );
+        }
+        SubCommand::RemoveRoom { room_id } => {
+            let (cache, _rx) = open_graph(config)?;
+            if cache.graph.remove_room(&room_id)? {
+                println!("Removed and blocked {}", room_id);
+            } else {
+                println!("{} is not part of the graph. Blocked it anyway", room_id);
+            }
+            cache.flush()?;
+        }
+        SubCommand::UnblockRoom { room_id } => {
+            let (cache, _rx) = open_graph(config)?;
+            if cache.graph.unblock_room(&room_id)? {
+                println!("Unblocked {}", room_id);
+            } else {
+                println!("{} was not blocked", room_id);
+            }
+            cache.flush()?;
+        }
+        SubCommand::RemoveLink { parent, child } => {
+            let (cache, _rx) = open_graph(config)?;
+            if cache.graph.remove_child(&parent, &child)? {
+                println!("Removed the link from {} to {}", parent, child);
+            } else {
+                println!("There is no link from {} to {}", parent, child);
+            }
+            cache.flush()?;
+        }
+        SubCommand::PruneRooms => {
+            let (cache, _rx) = open_graph(config)?;
+            println!("Removed {} rooms", cache.graph.prune_rooms()?);
+            cache.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the cache for changing the graph without the bot.
+///
+/// Postgres is not queried by the graph commands. The receiver has to be kept while the graph is
+/// used as nobody else listens for the changes.
+#[tracing::instrument]
+fn open_graph(config: &Config) -> Result<(CacheDb, watch::Receiver<Option<WsMessage>>)> {
+    let (tx, rx) = watch::channel(None);
+    let pool = PgPoolOptions::new().connect_lazy(&config.postgres.url)?;
+    let cache = CacheDb::new(tx, pool, &config.storage);
+    cache.migrate()?;
+    Ok((cache, rx))
 }
 
 pub static MATRIX_CLIENT: OnceCell<Client> = OnceCell::new();
@@ -136,6 +241,10 @@ async fn main() -> Result<()> {
 
     info!("Loading Configs...");
     let config = Config::load(opts.config)?;
+    if let Some(subcommand) = opts.subcommand {
+        return run_subcommand(subcommand, &config);
+    }
+    config.storage.check()?;
 
     let (tx, rx) = watch::channel(None);
 
@@ -145,7 +254,8 @@ async fn main() -> Result<()> {
         .max_connections(100)
         .connect(postgres_url)
         .await?;
-    let cache = CacheDb::new(tx, pool.clone());
+    let cache = CacheDb::new(tx, pool.clone(), &config.storage);
+    cache.migrate()?;
 
     if config.bot.force_cleanup {
         let cloned_cache = cache.clone();
@@ -157,31 +267,45 @@ async fn main() -> Result<()> {
 
     let cloned_cache = cache.clone();
     let cloned_config = config.clone();
-    let (exporter, recorder) = init_prometheus();
+    let (exporter, gauges) = init_prometheus();
+    let recorder = gauges.rooms;
+    let outdated_gauge = gauges.outdated_servers;
+    let metrics = Metrics::new(&config, cache.clone(), gauges.spooled_points)?;
 
     let span = info_span!("Get Versions and start sheduler");
     let pool_clone = pool.clone();
     tokio::spawn(
         async move {
-            let influx_db = InfluxDb::new(&cloned_config);
             let cache = cloned_cache.clone();
             // Get servers once
             if let Err(e) = crate::jobs::find_servers(&pool_clone, &cache, &cloned_config).await {
                 error!("Error servers: {}", e);
             }
 
-            if let Err(e) = crate::jobs::update_versions(&cache, influx_db.clone()).await {
+            if let Err(e) = crate::jobs::update_versions(
+                &cache,
+                metrics.clone(),
+                &cloned_config,
+                &outdated_gauge,
+            )
+            .await
+            {
                 error!("Error versions: {}", e);
             }
 
+            if let Err(e) = crate::jobs::update_keys(&cache, metrics.clone()).await {
+                error!("Error keys: {}", e);
+            }
+
             // Starting sheduler
             info!("Starting sheduler");
 
             start_queue(
                 cache,
-                influx_db,
+                metrics,
                 cloned_config.clone(),
                 Arc::new(recorder),
+                outdated_gauge,
                 pool_clone,
             )
             .await
@@ -191,32 +315,38 @@ async fn main() -> Result<()> {
     );
 
     info!("Starting webserver...");
-    run_server(&config, cache, rx, exporter).await;
+    run_server(&config, cache.clone(), rx, exporter).await;
 
+    // Writes since the last periodic flush would get lost otherwise
+    if let Err(e) = cache.flush() {
+        error!("Failed to flush the cache: {}", e);
+    }
     pool.close().await;
     opentelemetry::global::shutdown_tracer_provider();
     std::process::exit(0);
 }
 
-#[tracing::instrument(skip(influx_db, config, cache, recorder))]
+#[tracing::instrument(skip(metrics, config, cache, recorder, outdated_gauge))]
 async fn start_queue(
     cache: CacheDb,
-    influx_db: InfluxDb,
+    metrics: Metrics,
     config: Config,
     recorder: Arc<GenericGauge<AtomicF64>>,
+    outdated_gauge: IntGaugeVec,
     pgpool: PgPool,
 ) -> Result<()> {
     let mut sched = JobScheduler::new();
 
     let cache_two = cache.clone();
     let pgpool_two = pgpool.clone();
+    let config_two = config.clone();
     sched
         .add(
             //Should be */30
             Job::new("0 */30 * * * *", move |_, _| {
                 let cache = cache_two.clone();
                 let pgpool = pgpool_two.clone();
-                let config = config.clone();
+                let config = config_two.clone();
                 let span = debug_span!("Start sheduled find_servers");
                 tokio::spawn(
                     async move {
@@ -234,18 +364,194 @@ async fn start_queue(
         )
         .expect("failed to shedule job");
 
+    let cache_four = cache.clone();
+    let config_three = config.clone();
+    sched
+        .add(
+            //Should be */10
+            Job::new("0 */10 * * * *", move |_, _| {
+                let cache = cache_four.clone();
+                let config = config.clone();
+                let span = debug_span!("Start sheduled refresh_servers");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::refresh_servers(&cache, &config).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_five = cache.clone();
+    let metrics_two = metrics.clone();
+    sched
+        .add(
+            //Should be */30
+            Job::new("0 */30 * * * *", move |_, _| {
+                let cache = cache_five.clone();
+                let metrics = metrics_two.clone();
+                let span = debug_span!("Start sheduled update_keys");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) =
+                            crate::jobs::update_keys(&cache.clone(), metrics.clone()).await
+                        {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let metrics_three = metrics.clone();
+    sched
+        .add(
+            // Backoff per sink is handled by the spool
+            Job::new("0 * * * * *", move |_, _| {
+                let metrics = metrics_three.clone();
+                let span = debug_span!("Start sheduled replay_metrics");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::replay_metrics(metrics).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_six = cache.clone();
+    let metrics_four = metrics.clone();
+    sched
+        .add(
+            Job::new("0 */10 * * * *", move |_, _| {
+                let cache = cache_six.clone();
+                let metrics = metrics_four.clone();
+                let span = debug_span!("Start sheduled update_graph_stats");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::update_graph_stats(&cache, metrics).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_seven = cache.clone();
+    let metrics_five = metrics.clone();
+    sched
+        .add(
+            Job::new("0 */5 * * * *", move |_, _| {
+                let cache = cache_seven.clone();
+                let metrics = metrics_five.clone();
+                let span = debug_span!("Start sheduled update_reachability");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::update_reachability(&cache, metrics).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_eight = cache.clone();
+    let metrics_six = metrics.clone();
+    sched
+        .add(
+            Job::new("0 */15 * * * *", move |_, _| {
+                let cache = cache_eight.clone();
+                let metrics = metrics_six.clone();
+                let span = debug_span!("Start sheduled update_room_members");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::update_room_members(&cache, metrics).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_ten = cache.clone();
+    sched
+        .add(
+            Job::new("0 */30 * * * *", move |_, _| {
+                let cache = cache_ten.clone();
+                let span = debug_span!("Start sheduled refresh_relations");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::refresh_relations(&cache).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
+    let cache_nine = cache.clone();
+    let metrics_seven = metrics.clone();
+    sched
+        .add(
+            Job::new("0 */5 * * * *", move |_, _| {
+                let cache = cache_nine.clone();
+                let metrics = metrics_seven.clone();
+                let span = debug_span!("Start sheduled update_crawl_activity");
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = crate::jobs::update_crawl_activity(&cache, metrics).await {
+                            error!("Error: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            })
+            .unwrap(),
+        )
+        .expect("failed to shedule job");
+
     let cache_three = cache.clone();
     sched
         .add(
             //Should be 5m
             Job::new("0 */5 * * * *", move |_, _| {
                 let cache = cache_three.clone();
-                let influx_db = influx_db.clone();
+                let metrics = metrics.clone();
+                let config = config_three.clone();
+                let outdated_gauge = outdated_gauge.clone();
                 let span = debug_span!("Start sheduled update_versions");
                 tokio::spawn(
                     async move {
-                        if let Err(e) =
-                            crate::jobs::update_versions(&cache.clone(), influx_db.clone()).await
+                        if let Err(e) = crate::jobs::update_versions(
+                            &cache.clone(),
+                            metrics.clone(),
+                            &config,
+                            &outdated_gauge,
+                        )
+                        .await
                         {
                             error!("Error: {}", e);
                         }
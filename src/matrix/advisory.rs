@@ -0,0 +1,82 @@
+use crate::{config::Advisory, database::cache::CacheDb, matrix::MatrixVersionServer};
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{ParsedServerVersion, ServerImplementation};
+
+/// A server running a version affected by at least one advisory
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct FlaggedServer {
+    pub(crate) server_name: String,
+    pub(crate) implementation: ServerImplementation,
+    pub(crate) server: MatrixVersionServer,
+    pub(crate) advisories: Vec<Advisory>,
+}
+
+/// Returns the advisories which apply to the version.
+///
+/// Versions which can not be parsed are never flagged as we can not tell if they are affected.
+pub fn affecting_advisories<'a>(
+    advisories: &'a [Advisory],
+    version: &MatrixVersionServer,
+) -> Vec<&'a Advisory> {
+    let ParsedServerVersion {
+        implementation,
+        version,
+    } = version.parse();
+    let current = match version {
+        Some(current) => current,
+        None => return vec![],
+    };
+
+    advisories
+        .iter()
+        .filter(|advisory| advisory.implementation == implementation)
+        .filter(|advisory| current < advisory.minimum_version)
+        .collect()
+}
+
+/// Checks the cached version of every server against the advisories and updates the stored flags
+#[tracing::instrument(skip(advisories, cache))]
+pub fn flag_outdated_servers(
+    advisories: &[Advisory],
+    cache: &CacheDb,
+) -> Result<Vec<FlaggedServer>> {
+    let mut flagged = Vec::new();
+    let servers: Vec<String> = cache
+        .get_all_servers()
+        .filter_map(|x| x.ok())
+        .map(|server_name_bytes| {
+            String::from_utf8_lossy(server_name_bytes.as_ref()).replace("address/", "")
+        })
+        .collect();
+
+    for server_name in servers {
+        let version = match cache.get_server_version(&server_name)? {
+            Some(version) => version,
+            None => {
+                cache.remove_flagged_server(&server_name)?;
+                continue;
+            }
+        };
+
+        let affecting = affecting_advisories(advisories, &version);
+        if affecting.is_empty() {
+            cache.remove_flagged_server(&server_name)?;
+            continue;
+        }
+
+        let flagged_server = FlaggedServer {
+            implementation: ServerImplementation::from_name(&version.name),
+            server_name,
+            server: version,
+            advisories: affecting.into_iter().cloned().collect(),
+        };
+        cache.set_flagged_server(&flagged_server)?;
+        flagged.push(flagged_server);
+    }
+
+    info!("{} servers are affected by advisories", flagged.len());
+    Ok(flagged)
+}
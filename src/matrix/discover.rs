@@ -1,121 +1,405 @@
+use crate::config::{Dns, DnsProtocol, DnsResolver};
 use crate::errors::Errors;
-use regex::Regex;
-use reqwest::StatusCode;
-use serde::Deserialize;
+use chrono::prelude::*;
+use reqwest::{
+    header::{HeaderMap, CACHE_CONTROL, EXPIRES},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::debug;
 use trust_dns_resolver::{
-    config::{ResolverConfig, ResolverOpts},
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
     TokioAsyncResolver,
 };
 
-#[derive(Debug)]
-pub enum MatrixSsServername {
-    Ip(SocketAddr),
-    Host(String),
+const DEFAULT_FEDERATION_PORT: u16 = 8448;
+
+/// Used for the well-known file if the response has no caching headers
+const WELL_KNOWN_DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Upper limit for the caching of the well-known file
+const WELL_KNOWN_MAX_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+/// Used when there is no well-known file or when the resolution failed
+pub const ERROR_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The result of the server discovery.
+///
+/// `address` is where the connection has to be opened to while `host` is the value which has to
+/// be used for the HOST header and as TLS SNI. The certificate has to be valid for `host` too.
+///
+/// `resolved_at` and `expires_at` are unix timestamps in milliseconds. The expiry is the smallest
+/// TTL of all the DNS records and well-known responses used during the resolution.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatrixSsServername {
+    pub address: SocketAddr,
+    pub host: String,
+    pub resolved_at: i64,
+    pub expires_at: i64,
+}
+
+impl MatrixSsServername {
+    fn new(address: SocketAddr, host: String, ttl: Duration) -> Self {
+        let resolved_at = Utc::now().timestamp_millis();
+        MatrixSsServername {
+            address,
+            host,
+            resolved_at,
+            expires_at: resolved_at + ttl.as_millis() as i64,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now().timestamp_millis()
+    }
+
+    /// Returns true if the connection details differ. The timestamps are ignored.
+    pub fn differs_from(&self, other: &MatrixSsServername) -> bool {
+        self.address != other.address || self.host != other.host
+    }
+
+    /// The host without port. Used for SNI and the certificate validation.
+    pub fn tls_name(&self) -> &str {
+        split_host_port(&self.host)
+            .map(|(hostname, _)| hostname)
+            .unwrap_or(&self.host)
+    }
+
+    /// Builds the url for a federation endpoint.
+    ///
+    /// The url uses the tls name so that SNI and the certificate validation work as required. The
+    /// client from [`MatrixSsServername::client`] makes sure it connects to the resolved address.
+    pub fn federation_url(&self, path: &str) -> String {
+        let tls_name = self.tls_name();
+        if IpAddr::from_str(tls_name).is_ok() {
+            format!("https://{}{}", self.address, path)
+        } else {
+            format!("https://{}:{}{}", tls_name, self.address.port(), path)
+        }
+    }
+
+    /// Builds a client which resolves the tls name to the already resolved address.
+    ///
+    /// Requests still need to set the HOST header to `host` as it may differ from the url.
+    pub fn client(&self) -> Result<reqwest::Client, Errors> {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .user_agent(crate::APP_USER_AGENT)
+            .resolve(self.tls_name(), self.address)
+            .build()?;
+        Ok(client)
+    }
 }
 
 impl fmt::Display for MatrixSsServername {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            MatrixSsServername::Ip(ip) => write!(f, "{}", ip),
-            MatrixSsServername::Host(host) => write!(f, "{}", host),
+        write!(f, "{}", self.address)
+    }
+}
+
+/// Builds the resolver used for the server discovery from the dns config section.
+#[tracing::instrument(skip(config))]
+pub fn build_resolver(config: &Dns) -> Result<TokioAsyncResolver, Errors> {
+    let resolver = match config.resolver {
+        DnsResolver::System => TokioAsyncResolver::tokio_from_system_conf(),
+        DnsResolver::Quad9 => {
+            TokioAsyncResolver::tokio(ResolverConfig::quad9_tls(), ResolverOpts::default())
+        }
+        DnsResolver::Custom => {
+            if config.nameservers.is_empty() {
+                return Err(Errors::DnsResolverError(
+                    "The custom resolver requires at least one nameserver".into(),
+                ));
+            }
+            if let Some(nameserver) = config
+                .nameservers
+                .iter()
+                .find(|x| x.protocol == DnsProtocol::Tls && x.tls_dns_name.is_none())
+            {
+                return Err(Errors::DnsResolverError(format!(
+                    "The tls nameserver {} requires a tls_dns_name",
+                    nameserver.address
+                )));
+            }
+            let name_servers: Vec<NameServerConfig> = config
+                .nameservers
+                .iter()
+                .map(|nameserver| NameServerConfig {
+                    socket_addr: nameserver.address,
+                    protocol: match nameserver.protocol {
+                        DnsProtocol::Udp => Protocol::Udp,
+                        DnsProtocol::Tcp => Protocol::Tcp,
+                        DnsProtocol::Tls => Protocol::Tls,
+                    },
+                    tls_dns_name: nameserver.tls_dns_name.clone(),
+                    trust_nx_responses: true,
+                    tls_config: None,
+                })
+                .collect();
+            TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], name_servers),
+                ResolverOpts::default(),
+            )
         }
+    };
+    resolver.map_err(|e| Errors::DnsResolverError(e.to_string()))
+}
+
+/// Splits a server_name or delegated server name into hostname and optional port.
+///
+/// IPv6 literals are expected to be wrapped in brackets as defined in the spec. The brackets are
+/// removed from the returned hostname.
+fn split_host_port(server_name: &str) -> Result<(&str, Option<u16>), Errors> {
+    if let Some(rest) = server_name.strip_prefix('[') {
+        let end = rest
+            .find(']')
+            .ok_or(Errors::MatrixFederationWronglyConfigured)?;
+        let host = &rest[..end];
+        let port = match &rest[end + 1..] {
+            "" => None,
+            port => Some(
+                port.strip_prefix(':')
+                    .and_then(|port| port.parse().ok())
+                    .ok_or(Errors::MatrixFederationWronglyConfigured)?,
+            ),
+        };
+        return Ok((host, port));
+    }
+
+    // A bare IPv6 literal is not spec conform but some servers still show up like this
+    if IpAddr::from_str(server_name).is_ok() {
+        return Ok((server_name, None));
+    }
+
+    match server_name.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| Errors::MatrixFederationWronglyConfigured)?;
+            Ok((host, Some(port)))
+        }
+        None => Ok((server_name, None)),
     }
 }
 
-/// Resolves the server_name for usage with matrix S-S-Api according too https://matrix.org/docs/spec/server_server/latest#server-discovery
-/// It is required that the HOST header always gets set to the server_name when it is returning a IP.
-// TODO add more granular tracing
-#[tracing::instrument]
-pub async fn resolve_server_name(server_name: &str) -> Result<MatrixSsServername, Errors> {
-    // If ip literal with port
-    if let Ok(addr) = SocketAddr::from_str(server_name) {
-        return Ok(MatrixSsServername::Ip(addr));
-    } else if let Ok(ip) = IpAddr::from_str(server_name) {
-        // If ip without port
-        return Ok(MatrixSsServername::Ip(SocketAddr::new(ip, 8448)));
-    }
-
-    let resolver = TokioAsyncResolver::tokio(ResolverConfig::quad9_tls(), ResolverOpts::default())
+/// Converts the expiry of a DNS lookup into its remaining TTL.
+fn remaining_ttl(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+/// Resolves the A/AAAA records of a hostname and returns the first address with its TTL.
+#[tracing::instrument(skip(resolver))]
+async fn lookup_ip(
+    resolver: &TokioAsyncResolver,
+    hostname: &str,
+) -> Result<(IpAddr, Duration), Errors> {
+    let results = resolver
+        .lookup_ip(hostname)
+        .await
         .map_err(|_| Errors::MatrixFederationWronglyConfigured)?;
 
-    // If has hostname and port
-    let port_re = Regex::new(r":([0-9]+)$").unwrap();
-    if port_re.is_match(server_name) {
-        let caps = port_re.captures(server_name).unwrap();
-        let hostname = port_re.replace(server_name, "");
-        let port = caps.get(1).unwrap().as_str();
+    let ip = results
+        .iter()
+        .next()
+        .ok_or(Errors::MatrixFederationWronglyConfigured)?;
+    Ok((ip, remaining_ttl(results.valid_until())))
+}
 
-        // Get AAAA/A record
-        let results = resolver
-            .lookup_ip(hostname.to_string())
+/// Checks the `_matrix-fed._tcp` and the deprecated `_matrix._tcp` SRV records of a hostname.
+///
+/// The targets are tried in order of priority and weight until one of them resolves to an IP.
+/// The returned TTL is the smaller one of the SRV and the A/AAAA record.
+///
+/// A target of "." means the service is not available (RFC 2782) and fails the discovery.
+#[tracing::instrument(skip(resolver))]
+async fn lookup_srv(
+    resolver: &TokioAsyncResolver,
+    hostname: &str,
+) -> Result<Option<(SocketAddr, Duration)>, Errors> {
+    for service in &["_matrix-fed._tcp", "_matrix._tcp"] {
+        let results = match resolver
+            .srv_lookup(format!("{}.{}.", service, hostname))
             .await
-            .map_err(|_| Errors::MatrixFederationWronglyConfigured)?;
+        {
+            Ok(results) => results,
+            Err(_) => continue,
+        };
 
-        let ip: IpAddr = results
-            .iter()
-            .next()
-            .ok_or(Errors::MatrixFederationWronglyConfigured)?;
-        return Ok(MatrixSsServername::Ip(SocketAddr::new(
-            ip,
-            port.parse().unwrap(),
-        )));
+        let srv_ttl = remaining_ttl(results.as_lookup().valid_until());
+        let mut records: Vec<_> = results.iter().collect();
+        records.sort_by(|a, b| {
+            a.priority()
+                .cmp(&b.priority())
+                .then(b.weight().cmp(&a.weight()))
+        });
+
+        for record in records {
+            let target = record.target().to_string();
+            let target = target.trim_end_matches('.');
+            if target.is_empty() {
+                return Err(Errors::MatrixFederationUnavailable);
+            }
+            if let Ok((ip, ip_ttl)) = lookup_ip(resolver, target).await {
+                return Ok(Some((
+                    SocketAddr::new(ip, record.port()),
+                    srv_ttl.min(ip_ttl),
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Calculates how long a well-known response may be cached based on the Cache-Control and
+/// Expires headers.
+fn well_known_ttl(headers: &HeaderMap) -> Duration {
+    if let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|x| x.to_str().ok()) {
+        for directive in cache_control.split(',').map(|x| x.trim()) {
+            if directive == "no-cache" || directive == "no-store" {
+                return Duration::from_secs(0);
+            }
+            if let Some(max_age) = directive.strip_prefix("max-age=") {
+                if let Ok(max_age) = max_age.trim_matches('"').parse() {
+                    return Duration::from_secs(max_age).min(WELL_KNOWN_MAX_TTL);
+                }
+            }
+        }
     }
 
-    // Check well-known file
+    if let Some(expires) = headers.get(EXPIRES).and_then(|x| x.to_str().ok()) {
+        // An invalid date means the response is already expired
+        return match DateTime::parse_from_rfc2822(expires) {
+            Ok(expires) => (expires.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .min(WELL_KNOWN_MAX_TTL),
+            Err(_) => Duration::from_secs(0),
+        };
+    }
+
+    WELL_KNOWN_DEFAULT_TTL
+}
+
+/// Fetches the delegated server name from the well-known file.
+///
+/// The hostname is resolved with the configured resolver instead of the system one.
+/// Any invalid response is treated as if there was no well-known file at all.
+#[tracing::instrument(skip(resolver))]
+async fn lookup_well_known(
+    resolver: &TokioAsyncResolver,
+    hostname: &str,
+) -> Result<Option<(String, Duration)>, Errors> {
+    let ip = match lookup_ip(resolver, hostname).await {
+        Ok((ip, _)) => ip,
+        Err(_) => return Ok(None),
+    };
     let client = reqwest::ClientBuilder::new()
         .timeout(Duration::from_secs(30))
         .user_agent(crate::APP_USER_AGENT)
+        .resolve(hostname, SocketAddr::new(ip, 443))
         .build()?;
 
     let resp = client
-        .get(&format!(
-            "https://{}/.well-known/matrix/server",
-            server_name
-        ))
+        .get(format!("https://{}/.well-known/matrix/server", hostname))
         .send()
         .await;
 
     if let Ok(resp) = resp {
-        if resp.status() == StatusCode::OK || resp.status().is_redirection() {
-            let body: MatrixSsWellKnown = resp
-                .json::<MatrixSsWellKnown>()
-                .await
-                .map_err(|_| Errors::MatrixFederationWronglyConfigured)?;
+        if resp.status() == StatusCode::OK {
+            let ttl = well_known_ttl(resp.headers());
+            if let Ok(body) = resp.json::<MatrixSsWellKnown>().await {
+                if split_host_port(&body.server).is_ok() {
+                    return Ok(Some((body.server, ttl)));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
 
-            return if port_re.is_match(&body.server) {
-                let caps = port_re.captures(&body.server).unwrap();
-                let hostname = port_re.replace(&body.server, "");
-                let port = caps.get(1).unwrap().as_str();
-                Ok(MatrixSsServername::Host(format!("{}:{}", hostname, port)))
-            } else {
-                // FIXME if we have a hostname and no port we actually shall check SRV first
-                Ok(MatrixSsServername::Host(format!("{}:8448", body.server)))
-            };
+/// Resolves a hostname without an explicit port as defined by the SRV and fallback steps.
+async fn resolve_without_port(
+    resolver: &TokioAsyncResolver,
+    hostname: &str,
+) -> Result<(SocketAddr, Duration), Errors> {
+    if let Some(result) = lookup_srv(resolver, hostname).await? {
+        return Ok(result);
+    }
+    let (ip, ttl) = lookup_ip(resolver, hostname).await?;
+    Ok((SocketAddr::new(ip, DEFAULT_FEDERATION_PORT), ttl))
+}
+
+/// Resolves the server_name for usage with matrix S-S-Api according too https://matrix.org/docs/spec/server_server/latest#server-discovery
+/// It is required that the HOST header always gets set to the returned host.
+#[tracing::instrument(skip(resolver))]
+pub async fn resolve_server_name(
+    server_name: &str,
+    resolver: &TokioAsyncResolver,
+) -> Result<MatrixSsServername, Errors> {
+    let (hostname, port) = split_host_port(server_name)?;
+
+    // 1. If ip literal use it with the given port or 8448
+    if let Ok(ip) = IpAddr::from_str(hostname) {
+        return Ok(MatrixSsServername::new(
+            SocketAddr::new(ip, port.unwrap_or(DEFAULT_FEDERATION_PORT)),
+            server_name.to_string(),
+            WELL_KNOWN_MAX_TTL,
+        ));
+    }
+
+    // 2. If has hostname and port
+    if let Some(port) = port {
+        let (ip, ttl) = lookup_ip(resolver, hostname).await?;
+        return Ok(MatrixSsServername::new(
+            SocketAddr::new(ip, port),
+            server_name.to_string(),
+            ttl,
+        ));
+    }
+
+    // 3. Check well-known file
+    if let Some((delegated, well_known_ttl)) = lookup_well_known(resolver, hostname).await? {
+        debug!("{} is delegated to {}", server_name, delegated);
+        let (delegated_hostname, delegated_port) = split_host_port(&delegated)?;
+
+        // 3.1 Delegated to an ip literal
+        if let Ok(ip) = IpAddr::from_str(delegated_hostname) {
+            return Ok(MatrixSsServername::new(
+                SocketAddr::new(ip, delegated_port.unwrap_or(DEFAULT_FEDERATION_PORT)),
+                delegated,
+                well_known_ttl,
+            ));
+        }
+
+        // 3.2 Delegated to a hostname with port
+        if let Some(delegated_port) = delegated_port {
+            let (ip, ttl) = lookup_ip(resolver, delegated_hostname).await?;
+            return Ok(MatrixSsServername::new(
+                SocketAddr::new(ip, delegated_port),
+                delegated,
+                well_known_ttl.min(ttl),
+            ));
         }
+
+        // 3.3 and 3.4 Delegated to a hostname without port
+        let (address, ttl) = resolve_without_port(resolver, delegated_hostname).await?;
+        return Ok(MatrixSsServername::new(
+            address,
+            delegated_hostname.to_string(),
+            well_known_ttl.min(ttl),
+        ));
     }
 
-    // Check SRV record
-    let results = resolver
-        .srv_lookup(format!("_matrix._tcp.{}", server_name))
-        .await;
-    if let Ok(results) = results {
-        let first = results
-            .iter()
-            .next()
-            .ok_or(Errors::MatrixFederationWronglyConfigured)?;
-        let target = first.target().to_string();
-        let host = target.trim_end_matches('.');
-        let port = first.port();
-        return Ok(MatrixSsServername::Ip(SocketAddr::new(
-            IpAddr::from_str(host).map_err(|_| Errors::MatrixFederationWronglyConfigured)?,
-            port,
-        )));
-    }
-    Ok(MatrixSsServername::Host(format!("{}:8448", server_name)))
+    // 4. and 5. Check SRV records and fall back to the hostname itself.
+    // The missing well-known file is only cached for an hour.
+    let (address, ttl) = resolve_without_port(resolver, hostname).await?;
+    Ok(MatrixSsServername::new(
+        address,
+        hostname.to_string(),
+        ERROR_TTL.min(ttl),
+    ))
 }
 
 #[derive(Debug, Deserialize)]
@@ -0,0 +1,148 @@
+use crate::{database::cache::CacheDb, errors::Errors};
+use chrono::prelude::*;
+use color_eyre::eyre::Result;
+use reqwest::{header::HOST, StatusCode};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+
+/// The keys of a server as returned by `/_matrix/key/v2/server`
+#[derive(Debug, Deserialize)]
+struct ServerKeysResponse {
+    server_name: String,
+    valid_until_ts: i64,
+    verify_keys: BTreeMap<String, VerifyKey>,
+    #[serde(default)]
+    signatures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyKey {
+    key: String,
+}
+
+/// The signing keys of a server as they are stored in the cache
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct MatrixServerKeys {
+    /// Maps the key id to the unpadded base64 encoded public key
+    pub(crate) verify_keys: BTreeMap<String, String>,
+    pub(crate) valid_until_ts: i64,
+    /// True if the response was signed by all of its verify keys
+    pub(crate) signature_valid: bool,
+    pub(crate) fetched_at: i64,
+}
+
+impl MatrixServerKeys {
+    pub fn is_expired(&self) -> bool {
+        self.valid_until_ts <= Utc::now().timestamp_millis()
+    }
+}
+
+/// Serializes a json value as canonical json as defined in the appendices of the spec.
+///
+/// Object keys get sorted explicitly so that this does not depend on the serde_json features.
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let sorted: BTreeMap<&String, &Value> = map.iter().collect();
+            let entries: Vec<String> = sorted
+                .into_iter()
+                .map(|(key, value)| {
+                    format!("{}:{}", Value::String(key.clone()), canonical_json(value))
+                })
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(values) => {
+            let entries: Vec<String> = values.iter().map(canonical_json).collect();
+            format!("[{}]", entries.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Decodes unpadded base64 while also accepting padded input from non-conforming servers
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    base64::decode_config(input.trim_end_matches('='), base64::STANDARD_NO_PAD).ok()
+}
+
+/// Verifies that the response is signed by every verify key it contains.
+fn verify_self_signature(server_name: &str, raw: &Value, keys: &ServerKeysResponse) -> bool {
+    if keys.server_name != server_name || keys.verify_keys.is_empty() {
+        return false;
+    }
+    let signatures = match keys.signatures.get(server_name) {
+        Some(signatures) => signatures,
+        None => return false,
+    };
+
+    let mut signed = raw.clone();
+    if let Some(object) = signed.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+    let message = canonical_json(&signed);
+
+    keys.verify_keys.iter().all(|(key_id, verify_key)| {
+        let signature = signatures.get(key_id).and_then(|x| decode_base64(x));
+        let public_key = decode_base64(&verify_key.key);
+        match (signature, public_key) {
+            (Some(signature), Some(public_key)) => UnparsedPublicKey::new(&ED25519, public_key)
+                .verify(message.as_bytes(), &signature)
+                .is_ok(),
+            _ => false,
+        }
+    })
+}
+
+#[tracing::instrument(skip(cache))]
+pub async fn fetch_server_keys(server_name: &str, cache: &CacheDb) -> Result<()> {
+    let address = cache.get_server_address(server_name);
+    if let Some(address) = address {
+        let client = address.client()?;
+        let resp = client
+            .get(address.federation_url("/_matrix/key/v2/server"))
+            .header(HOST, address.host.as_str())
+            .send()
+            .await;
+        if let Ok(resp) = resp {
+            if resp.status() == StatusCode::OK {
+                let raw = resp
+                    .json::<Value>()
+                    .await
+                    .map_err(|_| Errors::MatrixFederationKeysWronglyConfigured)?;
+                let keys: ServerKeysResponse = serde_json::from_value(raw.clone())
+                    .map_err(|_| Errors::MatrixFederationKeysWronglyConfigured)?;
+
+                let current = MatrixServerKeys {
+                    signature_valid: verify_self_signature(server_name, &raw, &keys),
+                    verify_keys: keys
+                        .verify_keys
+                        .into_iter()
+                        .map(|(key_id, verify_key)| (key_id, verify_key.key))
+                        .collect(),
+                    valid_until_ts: keys.valid_until_ts,
+                    fetched_at: Utc::now().timestamp_millis(),
+                };
+                debug!(
+                    "{}: {} keys, signature valid: {}",
+                    server_name,
+                    current.verify_keys.len(),
+                    current.signature_valid
+                );
+
+                if let Some(previous) = cache.get_server_keys(server_name)? {
+                    if previous.verify_keys != current.verify_keys {
+                        info!("Signing keys of {} rotated", server_name);
+                        cache.record_key_rotation(server_name, &previous, &current)?;
+                    }
+                }
+                cache.set_server_keys(server_name, &current)?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,21 +1,61 @@
-use crate::{config::Config, database::cache::CacheDb, errors::Errors};
+use crate::{
+    config::{Config, Polling},
+    database::cache::CacheDb,
+    errors::Errors,
+};
+use chrono::prelude::*;
 use color_eyre::eyre::Result;
-use futures::TryStreamExt;
-use reqwest::StatusCode;
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::{header::HOST, StatusCode};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use tracing::{debug, info};
+use std::time::Instant;
+use tracing::{debug, error, info};
+use trust_dns_resolver::TokioAsyncResolver;
 
+mod advisory;
 mod discover;
+mod keys;
+mod reachability;
+mod version;
+
+pub use advisory::{flag_outdated_servers, FlaggedServer};
+pub use discover::MatrixSsServername;
+pub use keys::{fetch_server_keys, MatrixServerKeys};
+pub use reachability::{PollOutcome, Reachability};
+pub use version::{ParsedServerVersion, ParsedVersion, ServerImplementation};
 
 #[derive(sqlx::FromRow)]
 struct DestinationKey {
     destination: String,
 }
 
+/// Builds the resolver which is used for all servers of a job if it is enabled in the config
+fn build_shared_resolver(config: &Config) -> Result<Option<TokioAsyncResolver>, Errors> {
+    if config.dns.shared {
+        Ok(Some(discover::build_resolver(&config.dns)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the shared resolver or builds a new one for a single server
+fn get_resolver(
+    config: &Config,
+    shared_resolver: &Option<TokioAsyncResolver>,
+) -> Result<TokioAsyncResolver, Errors> {
+    match shared_resolver {
+        Some(resolver) => Ok(resolver.clone()),
+        None => discover::build_resolver(&config.dns),
+    }
+}
+
 #[tracing::instrument(skip(pool, config, cache))]
 pub async fn fetch_servers_from_db(pool: &PgPool, config: &Config, cache: &CacheDb) -> Result<()> {
     let postgres_query = config.postgres.query.as_ref();
+    let shared_resolver = build_shared_resolver(config)?;
+    let shared_resolver = &shared_resolver;
+
     let rows = sqlx::query_as::<_, DestinationKey>(postgres_query)
         .fetch(pool)
         .map_err(|e| Errors::DatabaseError(e.to_string()));
@@ -27,12 +67,25 @@ pub async fn fetch_servers_from_db(pool: &PgPool, config: &Config, cache: &Cache
                     return Ok(());
                 }
             }
-            let server_url = crate::matrix::discover::resolve_server_name(&row.destination).await;
+            let resolver = get_resolver(config, shared_resolver)?;
+            let server_url = discover::resolve_server_name(&row.destination, &resolver).await;
 
-            if let Ok(ref server_url) = server_url {
-                cache
-                    .set_server_address(&row.destination, server_url.to_string())
-                    .expect("Unable to write to sled");
+            match server_url {
+                Ok(ref server_url) => cache
+                    .set_server_address(&row.destination, server_url)
+                    .expect("Unable to write to sled"),
+                // Servers which never resolved don't get an address but are still tracked
+                Err(_) => {
+                    if let Err(e) = record_outcome(
+                        cache,
+                        &row.destination,
+                        PollOutcome::Dns,
+                        0,
+                        &config.polling,
+                    ) {
+                        error!("Failed to save reachability: {}", e);
+                    }
+                }
             }
             Ok(())
         })
@@ -43,38 +96,147 @@ pub async fn fetch_servers_from_db(pool: &PgPool, config: &Config, cache: &Cache
     Ok(())
 }
 
-#[tracing::instrument(skip(client, cache))]
+/// Resolves all servers again whose cached address expired
+#[tracing::instrument(skip(config, cache))]
+pub async fn refresh_server_addresses(config: &Config, cache: &CacheDb) -> Result<()> {
+    let shared_resolver = build_shared_resolver(config)?;
+    let shared_resolver = &shared_resolver;
+
+    let servers: Vec<String> = cache
+        .get_all_servers()
+        .filter_map(|x| x.ok())
+        .map(|server_name_bytes| {
+            String::from_utf8_lossy(server_name_bytes.as_ref()).replace("address/", "")
+        })
+        .collect();
+
+    stream::iter(servers)
+        .for_each_concurrent(None, |server_name| async move {
+            let previous = cache.get_server_address(&server_name);
+            if let Some(ref previous) = previous {
+                if !previous.is_expired() {
+                    return;
+                }
+            }
+
+            let resolver = match get_resolver(config, shared_resolver) {
+                Ok(resolver) => resolver,
+                Err(e) => {
+                    error!("Failed to build resolver: {}", e);
+                    return;
+                }
+            };
+            match discover::resolve_server_name(&server_name, &resolver).await {
+                Ok(current) => {
+                    if let Some(previous) = previous {
+                        if previous.differs_from(&current) {
+                            info!(
+                                "Address of {} changed from {} ({}) to {} ({})",
+                                server_name,
+                                previous.address,
+                                previous.host,
+                                current.address,
+                                current.host
+                            );
+                            if let Err(e) =
+                                cache.record_address_change(&server_name, &previous, &current)
+                            {
+                                error!("Failed to record address change: {}", e);
+                            }
+                        }
+                    }
+                    if let Err(e) = cache.set_server_address(&server_name, &current) {
+                        error!("Failed to save address: {}", e);
+                    }
+                }
+                Err(e) => {
+                    debug!("Failed to resolve {} again: {}", server_name, e);
+                    if let Err(e) =
+                        record_outcome(cache, &server_name, PollOutcome::Dns, 0, &config.polling)
+                    {
+                        error!("Failed to save reachability: {}", e);
+                    }
+                    // Keep the old address and only try again later
+                    if let Some(mut previous) = previous {
+                        previous.expires_at =
+                            Utc::now().timestamp_millis() + discover::ERROR_TTL.as_millis() as i64;
+                        if let Err(e) = cache.set_server_address(&server_name, &previous) {
+                            error!("Failed to save address: {}", e);
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+    Ok(())
+}
+
+#[tracing::instrument(skip(cache))]
 pub async fn fetch_server_version(
     server_name: &str,
-    client: &reqwest::Client,
     cache: &CacheDb,
+    polling: &Polling,
 ) -> Result<()> {
-    let address = cache.get_server_address(server_name);
-    if let Some(address) = address {
-        let address = String::from_utf8_lossy(address.as_ref());
-
-        let resp = client
-            .get(format!("https://{}/_matrix/federation/v1/version", address))
-            .send()
-            .await;
-        if let Ok(resp) = resp {
-            if resp.status() == StatusCode::OK {
-                let body = resp
-                    .json::<MatrixVersion>()
-                    .await
-                    .map_err(|_| Errors::MatrixFederationVersionWronglyConfigured);
-                if let Ok(body) = body {
-                    debug!(
-                        "{}: {} {}",
-                        server_name, body.server.name, body.server.version
-                    );
-                    {
-                        cache.set_server_version(server_name, body.server)?;
+    // Only successful polls use the latency
+    let mut latency_ms = 0;
+    let outcome = match cache.get_server_address(server_name) {
+        Some(address) => match address.client() {
+            Ok(client) => {
+                let start = Instant::now();
+                let resp = client
+                    .get(address.federation_url("/_matrix/federation/v1/version"))
+                    .header(HOST, address.host.as_str())
+                    .send()
+                    .await;
+                latency_ms = start.elapsed().as_millis() as i64;
+                match resp {
+                    Ok(resp) if resp.status() == StatusCode::OK => {
+                        match resp.json::<MatrixVersion>().await {
+                            Ok(body) => {
+                                debug!(
+                                    "{}: {} {}",
+                                    server_name, body.server.name, body.server.version
+                                );
+                                cache.set_server_version(server_name, body.server)?;
+                                PollOutcome::Success
+                            }
+                            Err(_) => PollOutcome::BadJson,
+                        }
                     }
+                    Ok(resp) => PollOutcome::HttpStatus(resp.status().as_u16()),
+                    Err(e) => PollOutcome::from_reqwest_error(&e),
                 }
             }
-        }
+            Err(Errors::ReqwestError(e)) => PollOutcome::from_reqwest_error(&e),
+            Err(_) => PollOutcome::Connect,
+        },
+        None => PollOutcome::Dns,
+    };
+
+    record_outcome(cache, server_name, outcome, latency_ms, polling)
+}
+
+/// Updates the reachability of the server with the outcome of a poll or resolution
+#[tracing::instrument(skip(cache, polling))]
+fn record_outcome(
+    cache: &CacheDb,
+    server_name: &str,
+    outcome: PollOutcome,
+    latency_ms: i64,
+    polling: &Polling,
+) -> Result<()> {
+    let previous = cache.get_reachability(server_name)?;
+    let was_dead = matches!(previous, Some(Reachability { dead: true, .. }));
+    let reachability = Reachability::record(previous, outcome, latency_ms, polling);
+    if reachability.dead && !was_dead {
+        info!("Marking {} as dead", server_name);
+    } else if !reachability.dead && was_dead {
+        info!("{} is reachable again", server_name);
     }
+    if outcome != PollOutcome::Success {
+        debug!("{}: polling failed with {}", server_name, outcome);
+    }
+    cache.set_reachability(server_name, &reachability)?;
 
     Ok(())
 }
@@ -84,8 +246,25 @@ pub struct MatrixVersion {
     pub(crate) server: MatrixVersionServer,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct MatrixVersionServer {
     pub(crate) name: String,
     pub(crate) version: String,
 }
+
+impl MatrixVersionServer {
+    pub fn parse(&self) -> ParsedServerVersion {
+        ParsedServerVersion {
+            implementation: ServerImplementation::from_name(&self.name),
+            version: ParsedVersion::parse(&self.version),
+        }
+    }
+}
+
+/// A version a server was running between `first_seen` and `last_seen` (unix timestamps in ms)
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VersionHistoryEntry {
+    pub(crate) server: MatrixVersionServer,
+    pub(crate) first_seen: i64,
+    pub(crate) last_seen: i64,
+}
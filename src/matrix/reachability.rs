@@ -0,0 +1,132 @@
+use crate::config::Polling;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt};
+
+/// The outcome of a single attempt to reach a server
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum PollOutcome {
+    Success,
+    /// The server has no usable address
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    /// The server answered with a status other than 200
+    HttpStatus(u16),
+    /// The server answered with a body we could not understand
+    BadJson,
+}
+
+impl PollOutcome {
+    /// Sorts a failed request into one of the outcome categories
+    pub fn from_reqwest_error(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return PollOutcome::Timeout;
+        }
+        if let Some(status) = error.status() {
+            return PollOutcome::HttpStatus(status.as_u16());
+        }
+        if error.is_decode() {
+            return PollOutcome::BadJson;
+        }
+
+        // Neither hyper nor rustls errors are exposed by reqwest so we have to look at the messages
+        let mut source = error.source();
+        while let Some(inner) = source {
+            let message = inner.to_string().to_lowercase();
+            if message.contains("dns error") {
+                return PollOutcome::Dns;
+            }
+            if message.contains("certificate") || message.contains("tls") {
+                return PollOutcome::Tls;
+            }
+            source = inner.source();
+        }
+        PollOutcome::Connect
+    }
+}
+
+impl fmt::Display for PollOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PollOutcome::Success => write!(f, "success"),
+            PollOutcome::Dns => write!(f, "dns"),
+            PollOutcome::Connect => write!(f, "connect"),
+            PollOutcome::Tls => write!(f, "tls"),
+            PollOutcome::Timeout => write!(f, "timeout"),
+            PollOutcome::HttpStatus(status) => write!(f, "http_{}", status),
+            PollOutcome::BadJson => write!(f, "bad_json"),
+        }
+    }
+}
+
+/// The poll history of a server as it is stored in the cache
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Reachability {
+    pub(crate) last_attempt: i64,
+    pub(crate) last_outcome: PollOutcome,
+    pub(crate) consecutive_failures: u32,
+    /// Start of the current streak of failures
+    pub(crate) failing_since: Option<i64>,
+    pub(crate) last_success: Option<i64>,
+    /// Duration of the last successful poll
+    pub(crate) latency_ms: Option<i64>,
+    pub(crate) dead: bool,
+}
+
+impl Reachability {
+    /// Returns the updated state after an attempt.
+    ///
+    /// Latency is only kept for successful attempts.
+    pub fn record(
+        previous: Option<Reachability>,
+        outcome: PollOutcome,
+        latency_ms: i64,
+        polling: &Polling,
+    ) -> Self {
+        let now = Utc::now().timestamp_millis();
+        let previous = previous.unwrap_or(Reachability {
+            last_attempt: now,
+            last_outcome: outcome,
+            consecutive_failures: 0,
+            failing_since: None,
+            last_success: None,
+            latency_ms: None,
+            dead: false,
+        });
+
+        if outcome == PollOutcome::Success {
+            return Reachability {
+                last_attempt: now,
+                last_outcome: outcome,
+                consecutive_failures: 0,
+                failing_since: None,
+                last_success: Some(now),
+                latency_ms: Some(latency_ms),
+                dead: false,
+            };
+        }
+
+        let failing_since = previous.failing_since.unwrap_or(now);
+        let dead_after = polling.dead_after_hours as i64 * 60 * 60 * 1000;
+        Reachability {
+            last_attempt: now,
+            last_outcome: outcome,
+            consecutive_failures: previous.consecutive_failures.saturating_add(1),
+            failing_since: Some(failing_since),
+            last_success: previous.last_success,
+            latency_ms: previous.latency_ms,
+            dead: now - failing_since >= dead_after,
+        }
+    }
+
+    /// Dead servers only get polled once per `dead_poll_interval_hours`
+    pub fn should_poll(&self, polling: &Polling) -> bool {
+        if !self.dead {
+            return true;
+        }
+        let interval = polling.dead_poll_interval_hours as i64 * 60 * 60 * 1000;
+        Utc::now().timestamp_millis() - self.last_attempt >= interval
+    }
+}
@@ -0,0 +1,235 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+static VERSION_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[vV]?(\d+)(?:\.(\d+))?(?:\.(\d+))?(.*)$").unwrap());
+static PRE_RELEASE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[-.~]?((?:rc|alpha|beta|pre|dev)\.?\d*|[ab]\d+)\b").unwrap());
+/// Candidates for a git hash. Only the ones containing a letter are used so that dates are skipped.
+static GIT_HASH_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(?:g)?([0-9a-f]{7,40})\b").unwrap());
+
+/// The homeserver implementations we know about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerImplementation {
+    Synapse,
+    Dendrite,
+    Conduit,
+    Construct,
+    Maelstrom,
+    Other,
+}
+
+impl ServerImplementation {
+    /// Maps the free-form name from the version endpoint to a known implementation
+    pub fn from_name(name: &str) -> Self {
+        let name = name.to_lowercase();
+        if name.contains("synapse") {
+            ServerImplementation::Synapse
+        } else if name.contains("dendrite") {
+            ServerImplementation::Dendrite
+        } else if name.contains("conduit") {
+            ServerImplementation::Conduit
+        } else if name.contains("construct") {
+            ServerImplementation::Construct
+        } else if name.contains("maelstrom") {
+            ServerImplementation::Maelstrom
+        } else {
+            ServerImplementation::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerImplementation::Synapse => "synapse",
+            ServerImplementation::Dendrite => "dendrite",
+            ServerImplementation::Conduit => "conduit",
+            ServerImplementation::Construct => "construct",
+            ServerImplementation::Maelstrom => "maelstrom",
+            ServerImplementation::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ServerImplementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ServerImplementation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "other" => Ok(ServerImplementation::Other),
+            name => match ServerImplementation::from_name(name) {
+                ServerImplementation::Other => Err(()),
+                implementation => Ok(implementation),
+            },
+        }
+    }
+}
+
+/// A semver-like version. Missing minor or patch components are treated as 0.
+///
+/// Ordering follows semver: a pre-release is lower than the release it belongs to. The build
+/// (usually a git hash) is ignored for ordering and equality.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParsedVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// For example `rc1` for Synapse release candidates
+    pub pre: Option<String>,
+    /// For example the git hash of a development build
+    pub build: Option<String>,
+}
+
+impl ParsedVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        let caps = VERSION_REGEX.captures(version.trim())?;
+        let component = |i: usize| {
+            caps.get(i)
+                .and_then(|x| x.as_str().parse().ok())
+                .unwrap_or(0)
+        };
+        let rest = caps.get(4).map(|x| x.as_str()).unwrap_or_default();
+
+        let pre = PRE_RELEASE_REGEX.captures(rest).map(|x| x[1].to_string());
+        let build = GIT_HASH_REGEX
+            .captures_iter(rest)
+            .map(|x| x[1].to_string())
+            .find(|x| x.bytes().any(|b| b.is_ascii_alphabetic()));
+
+        Some(ParsedVersion {
+            major: component(1),
+            minor: component(2),
+            patch: component(3),
+            pre,
+            build,
+        })
+    }
+}
+
+impl fmt::Display for ParsedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        if let Some(build) = &self.build {
+            write!(f, "+{}", build)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for ParsedVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ParsedVersion {}
+
+impl PartialOrd for ParsedVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ParsedVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => pre_release_key(a).cmp(&pre_release_key(b)),
+            })
+    }
+}
+
+/// Splits a pre-release like `rc10` or `beta.2` into its label and number so that the numbers
+/// compare numerically
+fn pre_release_key(pre: &str) -> (&str, Option<u64>) {
+    let number_start = pre.find(|c: char| c.is_ascii_digit()).unwrap_or(pre.len());
+    let label = pre[..number_start].trim_end_matches('.');
+    (label, pre[number_start..].parse().ok())
+}
+
+/// The implementation and version of a server parsed from its [`MatrixVersionServer`]
+///
+/// [`MatrixVersionServer`]: crate::matrix::MatrixVersionServer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ParsedServerVersion {
+    pub implementation: ServerImplementation,
+    /// None if the version string did not start with a number
+    pub version: Option<ParsedVersion>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(version: &str) -> ParsedVersion {
+        ParsedVersion::parse(version).unwrap()
+    }
+
+    #[test]
+    fn parses_synapse_versions() {
+        let version = parse("1.45.0rc2");
+        assert_eq!((version.major, version.minor, version.patch), (1, 45, 0));
+        assert_eq!(version.pre.as_deref(), Some("rc2"));
+        assert_eq!(version.build, None);
+
+        let version = parse("1.45.1 (b=matrix-org-hotfixes,3a4b5c6d7e)");
+        assert_eq!(version.pre, None);
+        assert_eq!(version.build.as_deref(), Some("3a4b5c6d7e"));
+    }
+
+    #[test]
+    fn parses_dendrite_and_conduit_versions() {
+        let version = parse("0.5.0+9a8b7c6");
+        assert_eq!((version.major, version.minor, version.patch), (0, 5, 0));
+        assert_eq!(version.build.as_deref(), Some("9a8b7c6"));
+
+        let version = parse("0.2.0");
+        assert_eq!((version.major, version.minor, version.patch), (0, 2, 0));
+        assert_eq!(version.pre, None);
+        assert_eq!(version.build, None);
+
+        let version = parse("v0.3");
+        assert_eq!((version.major, version.minor, version.patch), (0, 3, 0));
+    }
+
+    #[test]
+    fn skips_dates_as_hashes() {
+        assert_eq!(parse("0.1.0-20211015").build, None);
+        assert_eq!(
+            parse("0.1.0-20211015-gdeadbee").build.as_deref(),
+            Some("deadbee")
+        );
+    }
+
+    #[test]
+    fn orders_pre_releases_numerically() {
+        assert!(parse("1.45.0rc10") > parse("1.45.0rc2"));
+        assert!(parse("1.45.0rc1") < parse("1.45.0"));
+        assert!(parse("1.45.0beta.2") < parse("1.45.0beta.10"));
+        assert!(parse("1.45.0b1") < parse("1.45.0rc1"));
+        assert!(parse("1.44.0") < parse("1.45.0rc1"));
+    }
+
+    #[test]
+    fn ignores_the_build_for_ordering() {
+        assert_eq!(parse("0.5.0+9a8b7c6"), parse("0.5.0"));
+        assert_eq!(parse("1.10.0"), parse("1.10"));
+        assert!(parse("1.10.0") > parse("1.9.5"));
+    }
+}
@@ -1,33 +1,71 @@
-use crate::config::Config;
-use crate::database::cache::CacheDb;
+use crate::config::{Config, MetricsSinkConfig, LEGACY_INFLUXDB_SINK};
+use crate::database::cache::{CacheDb, CrawlCounter};
 use crate::errors::Errors;
 use futures::stream::{self, StreamExt};
 use influxdb_client::derives::PointSerialize;
-use influxdb_client::{Client, PointSerialize, Precision, Timestamp, TimestampOptions};
+use influxdb_client::{PointSerialize, Timestamp};
+use prometheus::IntGaugeVec;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::debug_span;
 use tracing::{error, info};
 use tracing_futures::Instrument;
 
+mod sinks;
+mod spool;
+
+pub use sinks::{InfluxDbV1Sink, InfluxDbV2Sink, LineProtocolFileSink, MetricsSink, NoopSink};
+pub use spool::Spool;
+
+/// Pushes points to all configured metrics sinks
 #[derive(Clone)]
-pub struct InfluxDb {
-    client: Arc<Client>,
+pub struct Metrics {
+    /// The sinks together with the configured name their failed points get spooled under
+    sinks: Arc<Vec<(String, Box<dyn MetricsSink>)>>,
+    spool: Arc<Spool>,
 }
 
-impl InfluxDb {
-    #[tracing::instrument(name = "InfluxDb::new", skip(config))]
-    pub fn new(config: &Config) -> Self {
-        let host = config.influxdb.clone().host;
-        let token = config.influxdb.clone().token;
-        let bucket = config.influxdb.clone().bucket;
-        let org = config.influxdb.clone().org;
-        let client = Client::new(host, token)
-            .with_bucket(bucket)
-            .with_org(org)
-            .with_precision(Precision::MS);
-        let client = Arc::new(client);
-        Self { client }
+impl Metrics {
+    #[tracing::instrument(name = "Metrics::new", skip(config, cache, spool_gauge))]
+    pub fn new(config: &Config, cache: CacheDb, spool_gauge: IntGaugeVec) -> Result<Self, Errors> {
+        let mut sinks: Vec<(String, Box<dyn MetricsSink>)> = Vec::new();
+        if let Some(influxdb) = &config.influxdb {
+            sinks.push((
+                LEGACY_INFLUXDB_SINK.to_string(),
+                Box::new(InfluxDbV2Sink::new(influxdb)?),
+            ));
+        }
+        for named in &config.metrics {
+            let sink: Box<dyn MetricsSink> = match &named.sink {
+                MetricsSinkConfig::InfluxDb2(influxdb) => Box::new(InfluxDbV2Sink::new(influxdb)?),
+                MetricsSinkConfig::InfluxDb1(influxdb) => Box::new(InfluxDbV1Sink::new(influxdb)?),
+                MetricsSinkConfig::File(file) => Box::new(LineProtocolFileSink::new(file)),
+                MetricsSinkConfig::Noop => Box::new(NoopSink),
+            };
+            sinks.push((named.name.clone(), sink));
+        }
+        if sinks.is_empty() {
+            info!("No metrics sinks configured. Points will be dropped");
+        }
+
+        let spool = Spool::new(cache, spool_gauge, config.storage.max_spooled_points);
+        for (sink_id, _) in &sinks {
+            spool.load_count(sink_id);
+        }
+
+        Ok(Self {
+            sinks: Arc::new(sinks),
+            spool: Arc::new(spool),
+        })
+    }
+
+    /// Retries the spooled points of all sinks which are not backing off
+    #[tracing::instrument(skip(self))]
+    pub async fn replay_spool(&self) -> color_eyre::Result<()> {
+        for (sink_id, sink) in self.sinks.iter() {
+            self.spool.replay(sink_id, sink.as_ref()).await?;
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, cache))]
@@ -41,6 +79,7 @@ impl InfluxDb {
                     std::str::from_utf8(server_name_bytes.as_ref()).unwrap();
                 server_name_untrimmed.replace("address/", "")
             })
+            .filter(|server_name| !cache.is_dead_server(server_name))
             .for_each(|server_name| {
                 match cache.get_server_version(&server_name) {
                     Ok(version) => {
@@ -49,9 +88,34 @@ impl InfluxDb {
                             let since_the_epoch =
                                 now.duration_since(UNIX_EPOCH).expect("Time went backwards");
 
+                            let parsed = version.parse();
+                            let (major, minor, patch, pre, build) = match parsed.version {
+                                Some(parsed_version) => (
+                                    parsed_version.major.to_string(),
+                                    parsed_version.minor.to_string(),
+                                    parsed_version.patch as i64,
+                                    parsed_version.pre.unwrap_or_default(),
+                                    parsed_version.build.unwrap_or_default(),
+                                ),
+                                None => (
+                                    "unknown".into(),
+                                    "unknown".into(),
+                                    0,
+                                    String::new(),
+                                    String::new(),
+                                ),
+                            };
+
                             let point = ServerVersion {
                                 server_name: server_name.to_string(),
+                                implementation: parsed.implementation.to_string(),
+                                major,
+                                minor,
+                                outdated: cache.is_flagged_server(&server_name).to_string(),
                                 version: format!("{} {}", version.name, version.version),
+                                patch,
+                                pre,
+                                build,
                                 timestamp: Timestamp::from(since_the_epoch.as_millis() as i64),
                             };
                             points.push(point);
@@ -64,37 +128,260 @@ impl InfluxDb {
                     }
                 }
             });
+        self.insert_points(points).await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, cache))]
+    pub async fn push_keys(&self, cache: &CacheDb) -> color_eyre::Result<()> {
+        let mut points: Vec<ServerKeys> = Vec::new();
+        cache
+            .get_all_servers()
+            .map(|x| {
+                let server_name_bytes = x.expect("unable to get bytes from server_keys");
+                let server_name_untrimmed =
+                    std::str::from_utf8(server_name_bytes.as_ref()).unwrap();
+                server_name_untrimmed.replace("address/", "")
+            })
+            .for_each(|server_name| match cache.get_server_keys(&server_name) {
+                Ok(Some(keys)) => {
+                    let now = SystemTime::now();
+                    let since_the_epoch =
+                        now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+
+                    points.push(ServerKeys {
+                        server_name,
+                        valid_until_ts: keys.valid_until_ts,
+                        expired: keys.is_expired(),
+                        signature_valid: keys.signature_valid,
+                        key_count: keys.verify_keys.len() as i64,
+                        timestamp: Timestamp::from(since_the_epoch.as_millis() as i64),
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to find server_keys: {}", e);
+                }
+            });
+        self.insert_points(points).await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, cache))]
+    pub async fn push_graph_stats(&self, cache: &CacheDb) -> color_eyre::Result<()> {
+        let stats = cache.graph.get_graph_stats();
+        self.insert_points(vec![GraphSize {
+            rooms: stats.rooms,
+            links: stats.links,
+            spaces: stats.spaces,
+            timestamp: current_timestamp(),
+        }])
+        .await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, cache))]
+    pub async fn push_reachability(&self, cache: &CacheDb) -> color_eyre::Result<()> {
+        let mut points: Vec<ServerReachability> = Vec::new();
+        cache
+            .get_all_servers()
+            .filter_map(|x| x.ok())
+            .map(|server_name_bytes| {
+                String::from_utf8_lossy(server_name_bytes.as_ref()).replace("address/", "")
+            })
+            .for_each(|server_name| match cache.get_reachability(&server_name) {
+                Ok(Some(reachability)) => points.push(ServerReachability {
+                    server_name,
+                    outcome: reachability.last_outcome.to_string(),
+                    dead: reachability.dead.to_string(),
+                    latency_ms: reachability.latency_ms.unwrap_or_default(),
+                    consecutive_failures: reachability.consecutive_failures as i64,
+                    timestamp: current_timestamp(),
+                }),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to find reachability: {}", e);
+                }
+            });
+        self.insert_points(points).await;
+        Ok(())
+    }
+
+    /// Only rooms which are part of the graph are pushed
+    #[tracing::instrument(skip(self, cache))]
+    pub async fn push_room_members(&self, cache: &CacheDb) -> color_eyre::Result<()> {
+        let points: Vec<RoomMembers> = cache
+            .graph
+            .get_synapse_joined_members_count()
+            .await
+            .into_iter()
+            .filter(|(room_id, _)| cache.graph.knows_room(room_id))
+            .map(|(room_id, members)| RoomMembers {
+                room_id,
+                members,
+                timestamp: current_timestamp(),
+            })
+            .collect();
+        self.insert_points(points).await;
+        Ok(())
+    }
+
+    /// The counters are totals since the first start so rates need to be derived in the query
+    #[tracing::instrument(skip(self, cache))]
+    pub async fn push_crawl_activity(&self, cache: &CacheDb) -> color_eyre::Result<()> {
+        self.insert_points(vec![CrawlActivity {
+            joins_attempted: cache.get_counter(CrawlCounter::JoinsAttempted)? as i64,
+            joins_succeeded: cache.get_counter(CrawlCounter::JoinsSucceeded)? as i64,
+            joins_failed: cache.get_counter(CrawlCounter::JoinsFailed)? as i64,
+            aliases_discovered: cache.get_counter(CrawlCounter::AliasesDiscovered)? as i64,
+            timestamp: current_timestamp(),
+        }])
+        .await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, points))]
+    async fn insert_points<T: PointSerialize>(&self, points: Vec<T>) {
         if points.is_empty() {
             info!("No points!");
-            return Ok(());
+            return;
         }
-        let span = debug_span!("Push points to influx-db");
-        stream::iter(points)
+        let lines: Vec<String> = points
+            .iter()
+            .map(|point| point.serialize_with_timestamp(None))
+            .collect();
+        let span = debug_span!("Push points to metrics sinks");
+        stream::iter(lines)
             .chunks(40)
             .for_each(|chunk| async move {
-                // Insert without timestamp - InfluxDB will automatically set the timestamp
-                if let Err(e) = self
-                    .client
-                    .insert_points(&chunk, TimestampOptions::None)
-                    .await
-                    .map_err(|e| Errors::InfluxDbError(format!("{:?}", e)))
-                {
-                    error!("Got influxdb error: {}", e);
-                };
+                for (sink_id, sink) in self.sinks.iter() {
+                    // Don't wait for a timeout on every chunk while a sink is known to be down
+                    if self.spool.is_backing_off(sink_id) {
+                        if let Err(e) = self.spool.store(sink_id, &chunk) {
+                            error!("Failed to spool points for {}: {}", sink_id, e);
+                        }
+                        continue;
+                    }
+                    match sink.write_lines(&chunk).await {
+                        Ok(()) => self.spool.succeeded(sink_id),
+                        Err(e) => {
+                            error!("Got error from {} sink: {}", sink_id, e);
+                            self.spool.failed(sink_id);
+                            if let Err(e) = self.spool.store(sink_id, &chunk) {
+                                error!("Failed to spool points for {}: {}", sink_id, e);
+                            }
+                        }
+                    }
+                }
             })
             .instrument(span)
             .await;
-        Ok(())
     }
 }
 
+fn current_timestamp() -> Timestamp {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    Timestamp::from(since_the_epoch.as_millis() as i64)
+}
+
 #[derive(PointSerialize)]
 #[point(measurement = "server_version")]
 struct ServerVersion {
     #[point(tag)]
     server_name: String,
+    #[point(tag)]
+    implementation: String,
+    #[point(tag)]
+    major: String,
+    #[point(tag)]
+    minor: String,
+    /// "true" if the server is affected by an advisory
+    #[point(tag)]
+    outdated: String,
     #[point(field = "version")]
     version: String,
+    #[point(field = "patch")]
+    patch: i64,
+    #[point(field = "pre")]
+    pre: String,
+    #[point(field = "build")]
+    build: String,
+    #[point(timestamp)]
+    timestamp: Timestamp,
+}
+
+#[derive(PointSerialize)]
+#[point(measurement = "server_keys")]
+struct ServerKeys {
+    #[point(tag)]
+    server_name: String,
+    #[point(field = "valid_until_ts")]
+    valid_until_ts: i64,
+    #[point(field = "expired")]
+    expired: bool,
+    #[point(field = "signature_valid")]
+    signature_valid: bool,
+    #[point(field = "key_count")]
+    key_count: i64,
+    #[point(timestamp)]
+    timestamp: Timestamp,
+}
+
+#[derive(PointSerialize)]
+#[point(measurement = "graph_size")]
+struct GraphSize {
+    #[point(field = "rooms")]
+    rooms: i64,
+    #[point(field = "links")]
+    links: i64,
+    #[point(field = "spaces")]
+    spaces: i64,
+    #[point(timestamp)]
+    timestamp: Timestamp,
+}
+
+#[derive(PointSerialize)]
+#[point(measurement = "server_reachability")]
+struct ServerReachability {
+    #[point(tag)]
+    server_name: String,
+    #[point(tag)]
+    outcome: String,
+    #[point(tag)]
+    dead: String,
+    /// Latency of the last successful poll
+    #[point(field = "latency_ms")]
+    latency_ms: i64,
+    #[point(field = "consecutive_failures")]
+    consecutive_failures: i64,
+    #[point(timestamp)]
+    timestamp: Timestamp,
+}
+
+#[derive(PointSerialize)]
+#[point(measurement = "room_members")]
+struct RoomMembers {
+    #[point(tag)]
+    room_id: String,
+    #[point(field = "members")]
+    members: i64,
+    #[point(timestamp)]
+    timestamp: Timestamp,
+}
+
+#[derive(PointSerialize)]
+#[point(measurement = "crawl_activity")]
+struct CrawlActivity {
+    #[point(field = "joins_attempted")]
+    joins_attempted: i64,
+    #[point(field = "joins_succeeded")]
+    joins_succeeded: i64,
+    #[point(field = "joins_failed")]
+    joins_failed: i64,
+    #[point(field = "aliases_discovered")]
+    aliases_discovered: i64,
     #[point(timestamp)]
     timestamp: Timestamp,
 }
@@ -0,0 +1,171 @@
+use crate::{
+    config::{FileSink, InfluxDb, InfluxDbV1},
+    errors::Errors,
+};
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use tracing::debug;
+
+/// A destination for points in the influxdb line protocol.
+///
+/// All points carry a timestamp in milliseconds.
+#[async_trait]
+pub trait MetricsSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn write_lines(&self, lines: &[String]) -> Result<(), Errors>;
+}
+
+fn http_client() -> Result<reqwest::Client, Errors> {
+    let client = reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .user_agent(crate::APP_USER_AGENT)
+        .build()?;
+    Ok(client)
+}
+
+/// Checks the response of a write request and turns failures into an [`Errors::InfluxDbError`]
+async fn check_write_response(resp: reqwest::Response) -> Result<(), Errors> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = resp.text().await.unwrap_or_default();
+    Err(Errors::InfluxDbError(format!("{}: {}", status, body)))
+}
+
+/// Writes to the `/api/v2/write` endpoint of InfluxDB 2
+pub struct InfluxDbV2Sink {
+    client: reqwest::Client,
+    config: InfluxDb,
+}
+
+impl InfluxDbV2Sink {
+    pub fn new(config: &InfluxDb) -> Result<Self, Errors> {
+        Ok(Self {
+            client: http_client()?,
+            config: config.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl MetricsSink for InfluxDbV2Sink {
+    fn name(&self) -> &'static str {
+        "influxdb2"
+    }
+
+    async fn write_lines(&self, lines: &[String]) -> Result<(), Errors> {
+        let resp = self
+            .client
+            .post(format!(
+                "{}/api/v2/write",
+                self.config.host.trim_end_matches('/')
+            ))
+            .header("Authorization", format!("Token {}", self.config.token))
+            .query(&[
+                ("org", self.config.org.as_str()),
+                ("bucket", self.config.bucket.as_str()),
+                ("precision", "ms"),
+            ])
+            .body(lines.join("\n"))
+            .send()
+            .await?;
+        check_write_response(resp).await
+    }
+}
+
+/// Writes to the `/write` endpoint of InfluxDB 1.x
+pub struct InfluxDbV1Sink {
+    client: reqwest::Client,
+    config: InfluxDbV1,
+}
+
+impl InfluxDbV1Sink {
+    pub fn new(config: &InfluxDbV1) -> Result<Self, Errors> {
+        Ok(Self {
+            client: http_client()?,
+            config: config.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl MetricsSink for InfluxDbV1Sink {
+    fn name(&self) -> &'static str {
+        "influxdb1"
+    }
+
+    async fn write_lines(&self, lines: &[String]) -> Result<(), Errors> {
+        let mut query = vec![("db", self.config.database.as_str()), ("precision", "ms")];
+        if let Some(retention_policy) = &self.config.retention_policy {
+            query.push(("rp", retention_policy.as_str()));
+        }
+
+        let mut request = self
+            .client
+            .post(format!("{}/write", self.config.host.trim_end_matches('/')))
+            .query(&query)
+            .body(lines.join("\n"));
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+
+        let resp = request.send().await?;
+        check_write_response(resp).await
+    }
+}
+
+/// Appends the points to a file, one line per point
+pub struct LineProtocolFileSink {
+    config: FileSink,
+    /// Prevents concurrent jobs from interleaving their writes
+    lock: Mutex<()>,
+}
+
+impl LineProtocolFileSink {
+    pub fn new(config: &FileSink) -> Self {
+        Self {
+            config: config.clone(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsSink for LineProtocolFileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn write_lines(&self, lines: &[String]) -> Result<(), Errors> {
+        let mut buf = lines.join("\n");
+        buf.push('\n');
+
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .await?;
+        file.write_all(buf.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Drops all points
+pub struct NoopSink;
+
+#[async_trait]
+impl MetricsSink for NoopSink {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    async fn write_lines(&self, lines: &[String]) -> Result<(), Errors> {
+        debug!("Dropping {} points", lines.len());
+        Ok(())
+    }
+}
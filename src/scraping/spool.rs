@@ -0,0 +1,174 @@
+use crate::database::cache::CacheDb;
+use color_eyre::Result;
+use prometheus::IntGaugeVec;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+use super::MetricsSink;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// Keeps points which could not be written to a sink in sled until the sink recovers.
+///
+/// The points are stored as serialized lines so they keep their original timestamps.
+/// If a sink has more than `max_points` spooled the oldest ones get dropped.
+pub struct Spool {
+    cache: CacheDb,
+    backoff: Mutex<HashMap<String, Backoff>>,
+    /// Makes sure only one replay runs at a time so points are not sent twice
+    replaying: tokio::sync::Mutex<()>,
+    gauge: IntGaugeVec,
+    /// Spooled points per sink. Only counted in sled once at startup
+    counts: Mutex<HashMap<String, usize>>,
+    max_points: usize,
+}
+
+impl Spool {
+    pub fn new(cache: CacheDb, gauge: IntGaugeVec, max_points: usize) -> Self {
+        Self {
+            cache,
+            backoff: Mutex::new(HashMap::new()),
+            replaying: tokio::sync::Mutex::new(()),
+            gauge,
+            counts: Mutex::new(HashMap::new()),
+            max_points,
+        }
+    }
+
+    /// True while the sink is failing and the backoff did not pass yet
+    pub fn is_backing_off(&self, sink_id: &str) -> bool {
+        let backoff = self.backoff.lock().unwrap();
+        backoff
+            .get(sink_id)
+            .map(|x| x.next_attempt > Instant::now())
+            .unwrap_or(false)
+    }
+
+    /// Starts or doubles the backoff of the sink. Only called for writes which failed.
+    pub fn failed(&self, sink_id: &str) {
+        let mut backoff = self.backoff.lock().unwrap();
+        let entry = backoff.entry(sink_id.to_string()).or_insert(Backoff {
+            failures: 0,
+            next_attempt: Instant::now(),
+        });
+        entry.failures = entry.failures.saturating_add(1);
+        let delay = MIN_BACKOFF
+            .checked_mul(2u32.saturating_pow(entry.failures - 1))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF);
+        entry.next_attempt = Instant::now() + delay;
+        info!(
+            "Backing off {} for {}s after {} failures",
+            sink_id,
+            delay.as_secs(),
+            entry.failures
+        );
+    }
+
+    pub fn succeeded(&self, sink_id: &str) {
+        let mut backoff = self.backoff.lock().unwrap();
+        if backoff.remove(sink_id).is_some() {
+            info!("Sink {} recovered", sink_id);
+        }
+    }
+
+    /// Stores the lines for a later replay
+    pub fn store(&self, sink_id: &str, lines: &[String]) -> Result<()> {
+        self.cache.spool_points(sink_id, lines)?;
+        let mut count = self.change_count(sink_id, lines.len(), 0);
+
+        let mut dropped = 0;
+        while count > self.max_points {
+            let (key, lines) = match self.cache.get_spooled_points(sink_id).next() {
+                Some(res) => res?,
+                None => break,
+            };
+            if self.cache.remove_spooled_points(&key)? {
+                count = self.change_count(sink_id, 0, lines.len());
+                dropped += lines.len();
+            }
+        }
+        if dropped > 0 {
+            warn!(
+                "Dropped {} spooled points of {} as the spool is full",
+                dropped, sink_id
+            );
+        }
+        Ok(())
+    }
+
+    /// Counts the points which are already spooled. Only needed once at startup.
+    pub fn load_count(&self, sink_id: &str) {
+        match self.cache.count_spooled_points(sink_id) {
+            Ok(count) => {
+                self.counts
+                    .lock()
+                    .unwrap()
+                    .insert(sink_id.to_string(), count);
+                self.update_gauge(sink_id, count);
+            }
+            Err(e) => error!("Failed to count spooled points: {}", e),
+        }
+    }
+
+    /// Returns the new number of spooled points of the sink
+    fn change_count(&self, sink_id: &str, added: usize, removed: usize) -> usize {
+        let count = {
+            let mut counts = self.counts.lock().unwrap();
+            let count = counts.entry(sink_id.to_string()).or_insert(0);
+            *count = (*count + added).saturating_sub(removed);
+            *count
+        };
+        self.update_gauge(sink_id, count);
+        count
+    }
+
+    fn update_gauge(&self, sink_id: &str, count: usize) {
+        self.gauge.with_label_values(&[sink_id]).set(count as i64);
+    }
+
+    /// Writes spooled points oldest first until the spool is empty or the sink fails again
+    #[tracing::instrument(skip(self, sink))]
+    pub async fn replay(&self, sink_id: &str, sink: &dyn MetricsSink) -> Result<()> {
+        if self.is_backing_off(sink_id) {
+            return Ok(());
+        }
+        let _guard = match self.replaying.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(()),
+        };
+
+        let mut replayed = 0;
+        for res in self.cache.get_spooled_points(sink_id) {
+            let (key, lines) = res?;
+            if let Err(e) = sink.write_lines(&lines).await {
+                error!("Failed to replay points to {}: {}", sink_id, e);
+                self.failed(sink_id);
+                break;
+            }
+            // The chunk may have been dropped by store in the meantime
+            if self.cache.remove_spooled_points(&key)? {
+                self.change_count(sink_id, 0, lines.len());
+            }
+            replayed += lines.len();
+        }
+        if replayed > 0 {
+            info!("Replayed {} points to {}", replayed, sink_id);
+            if !self.is_backing_off(sink_id) {
+                self.succeeded(sink_id);
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,16 +2,22 @@ use crate::{
     appservice::generate_appservice,
     config::Config,
     database::{cache::CacheDb, graph::GraphDb},
-    webpage::api::SSEJson,
+    matrix::ServerImplementation,
+    webpage::api::{
+        FlaggedServersJson, RoomAliasesJson, ServerVersionJson, ServerVersionsJson, VersionCount,
+        VersionHistoryJson, VersionStatsJson, WsMessage,
+    },
 };
 use futures::{SinkExt, StreamExt};
 use opentelemetry_prometheus::PrometheusExporter;
+use percent_encoding::percent_decode_str;
 use prometheus::{
     core::{AtomicF64, GenericGauge},
-    opts, register_gauge, Encoder, Registry, TextEncoder,
+    opts, register_gauge, register_int_gauge_vec, Encoder, IntGaugeVec, Registry, TextEncoder,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
     net::{IpAddr, SocketAddr},
     str::FromStr,
@@ -22,17 +28,45 @@ use tracing::{error, info};
 use warp::{filters::BoxedFilter, http::StatusCode, ws::Message, Filter, Rejection, Reply};
 
 pub mod api;
+pub mod metrics;
 
 #[derive(Deserialize)]
 struct Servers {
     include_members: bool,
 }
 
+#[derive(Deserialize, Debug)]
+struct VersionsQuery {
+    /// Only include servers of this implementation
+    implementation: Option<String>,
+    /// Only include servers with this version or a version starting with `{version}.`
+    version: Option<String>,
+    /// Either `json` (the default) or `csv`
+    format: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RemoveRoom {
+    room_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RemoveLink {
+    parent: String,
+    child: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
 #[tracing::instrument(skip(config, cache, rx, exporter))]
 pub async fn run_server(
     config: &Config,
     cache: CacheDb,
-    rx: Receiver<Option<SSEJson>>,
+    rx: Receiver<Option<WsMessage>>,
     exporter: PrometheusExporter,
 ) {
     info!("Starting appservice...");
@@ -43,6 +77,13 @@ pub async fn run_server(
     let graph_one = graph.clone();
     let graph_two = graph.clone();
     let graph_three = graph.clone();
+    let graph_four = graph.clone();
+    let cache_one = cache.clone();
+    let cache_two = cache.clone();
+    let cache_three = cache.clone();
+    let cache_four = cache.clone();
+    let cache_five = cache.clone();
+    let cache_six = cache.clone();
     info!("Path is: {} and {}", config.api.webpage_path, path);
 
     /*let log = warp::log::custom(|info| {
@@ -83,6 +124,38 @@ pub async fn run_server(
                         servers(graph, include_members).await
                     },
                 ))
+            .or(warp::path!("api" / "v0.1.0" / "versions")
+                .and(warp::query::<VersionsQuery>())
+                .map(move |query: VersionsQuery| (cache_three.clone(), query))
+                .and_then(|(cache, query): (CacheDb, VersionsQuery)| async move {
+                    server_versions(cache, query).await
+                }))
+            .or(warp::path!("api" / "v0.1.0" / "version-stats")
+                .and(warp::query::<VersionsQuery>())
+                .map(move |query: VersionsQuery| (cache_four.clone(), query))
+                .and_then(|(cache, query): (CacheDb, VersionsQuery)| async move {
+                    version_stats(cache, query).await
+                }))
+            .or(warp::any().and(
+                warp::path!("api" / "v0.1.0" / "versions" / String)
+                    .map(move |server_name: String| (cache_one.clone(), server_name))
+                    .and_then(|(cache, server_name): (CacheDb, String)| async move {
+                        version_history(cache, server_name).await
+                    }),
+            ))
+            .or(warp::path!("api" / "v0.1.0" / "rooms" / String)
+                .map(move |room_id_or_alias: String| (cache_five.clone(), room_id_or_alias))
+                .and_then(|(cache, room_id_or_alias): (CacheDb, String)| async move {
+                    room(cache, room_id_or_alias).await
+                }))
+            .or(warp::path!("api" / "v0.1.0" / "rooms" / String / "aliases")
+                .map(move |room_id_or_alias: String| (cache_six.clone(), room_id_or_alias))
+                .and_then(|(cache, room_id_or_alias): (CacheDb, String)| async move {
+                    room_aliases(cache, room_id_or_alias).await
+                }))
+            .or(warp::path!("api" / "v0.1.0" / "outdated")
+                .map(move || cache_two.clone())
+                .and_then(|cache: CacheDb| async { flagged_servers(cache).await }))
             .or(warp::fs::dir(config.api.webpage_path.to_string()).map(cache_header))
             .or(warp::path("spaces")
                 .and(warp::path::end())
@@ -101,7 +174,9 @@ pub async fn run_server(
                 .and(warp::fs::file(path.clone())))
             .or(warp::path::end().and(warp::get()).and(warp::fs::file(path)))
             .with(warp::compression::gzip()))
+        .or(warp::post().and(admin(graph_four, config.api.admin_token.clone())))
         .recover(handle_rejection)
+        .with(warp::log::custom(metrics::record_request))
         .with(warp::trace::request());
     //.with(log);
     if let Ok(addr) = addr {
@@ -134,18 +209,52 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     Ok(warp::reply::with_status(message, code))
 }
 
+/// The metrics which get updated from the jobs
+#[derive(Clone)]
+pub struct PrometheusGauges {
+    pub rooms: GenericGauge<AtomicF64>,
+    pub outdated_servers: IntGaugeVec,
+    pub spooled_points: IntGaugeVec,
+}
+
 #[tracing::instrument]
-pub fn init_prometheus() -> (PrometheusExporter, GenericGauge<AtomicF64>) {
+pub fn init_prometheus() -> (PrometheusExporter, PrometheusGauges) {
     let registry = Registry::new();
     let opts = opts!("rooms_total", "Rooms statistics").namespace("server_stats");
     let gauge = register_gauge!(opts).unwrap();
     registry
         .register(Box::new(gauge.clone()))
         .expect("Creating a prometheus registry");
+    let opts = opts!(
+        "outdated_servers",
+        "Servers affected by an advisory. Set to 1 for each affected server"
+    )
+    .namespace("server_stats");
+    let outdated_gauge = register_int_gauge_vec!(opts, &["server_name", "implementation"]).unwrap();
+    registry
+        .register(Box::new(outdated_gauge.clone()))
+        .expect("Creating a prometheus registry");
+    let opts = opts!(
+        "spooled_points",
+        "Points waiting to be replayed to a metrics sink"
+    )
+    .namespace("server_stats");
+    let spool_gauge = register_int_gauge_vec!(opts, &["sink"]).unwrap();
+    registry
+        .register(Box::new(spool_gauge.clone()))
+        .expect("Creating a prometheus registry");
+    metrics::register(&registry);
     let exporter = opentelemetry_prometheus::exporter()
         .with_registry(registry)
         .init();
-    (exporter, gauge)
+    (
+        exporter,
+        PrometheusGauges {
+            rooms: gauge,
+            outdated_servers: outdated_gauge,
+            spooled_points: spool_gauge,
+        },
+    )
 }
 
 #[tracing::instrument(skip(exporter))]
@@ -166,8 +275,95 @@ fn prometheus_route(exporter: PrometheusExporter) -> BoxedFilter<(impl Reply,)>
         .boxed()
 }
 
+/// Endpoints to remove rooms and links from the graph and to unblock removed rooms.
+/// Requires the admin token as bearer token.
+#[tracing::instrument(skip(graph, admin_token))]
+fn admin(graph: Arc<GraphDb>, admin_token: Option<String>) -> BoxedFilter<(impl Reply,)> {
+    let authorized = warp::header::optional::<String>("authorization").map(
+        move |header: Option<String>| match (&admin_token, header) {
+            (Some(token), Some(header)) => ring::constant_time::verify_slices_are_equal(
+                format!("Bearer {}", token).as_bytes(),
+                header.as_bytes(),
+            )
+            .is_ok(),
+            _ => false,
+        },
+    );
+    let graph_one = graph.clone();
+    let graph_two = graph.clone();
+    let graph_three = graph.clone();
+
+    warp::path!("api" / "v0.1.0" / "admin" / "remove_room")
+        .and(authorized.clone())
+        .and(warp::body::json())
+        .map(move |authorized: bool, body: RemoveRoom| {
+            if !authorized {
+                return error_reply("UNAUTHORIZED", StatusCode::UNAUTHORIZED);
+            }
+            match graph_one.remove_room(&body.room_id) {
+                Ok(removed) => {
+                    warp::reply::json(&serde_json::json!({ "removed": removed })).into_response()
+                }
+                Err(e) => {
+                    error!("Failed to remove room: {}", e);
+                    error_reply("UNABLE_TO_REMOVE_ROOM", StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        })
+        .or(warp::path!("api" / "v0.1.0" / "admin" / "unblock_room")
+            .and(authorized.clone())
+            .and(warp::body::json())
+            .map(move |authorized: bool, body: RemoveRoom| {
+                if !authorized {
+                    return error_reply("UNAUTHORIZED", StatusCode::UNAUTHORIZED);
+                }
+                match graph_three.unblock_room(&body.room_id) {
+                    Ok(unblocked) => {
+                        warp::reply::json(&serde_json::json!({ "unblocked": unblocked }))
+                            .into_response()
+                    }
+                    Err(e) => {
+                        error!("Failed to unblock room: {}", e);
+                        error_reply("UNABLE_TO_UNBLOCK_ROOM", StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                }
+            }))
+        .or(warp::path!("api" / "v0.1.0" / "admin" / "remove_link")
+            .and(authorized.clone())
+            .and(warp::body::json())
+            .map(move |authorized: bool, body: RemoveLink| {
+                if !authorized {
+                    return error_reply("UNAUTHORIZED", StatusCode::UNAUTHORIZED);
+                }
+                match graph_two.remove_child(&body.parent, &body.child) {
+                    Ok(removed) => warp::reply::json(&serde_json::json!({ "removed": removed }))
+                        .into_response(),
+                    Err(e) => {
+                        error!("Failed to remove link: {}", e);
+                        error_reply("UNABLE_TO_REMOVE_LINK", StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                }
+            }))
+        .or(warp::path!("api" / "v0.1.0" / "admin" / "prune_rooms")
+            .and(authorized)
+            .map(move |authorized: bool| {
+                if !authorized {
+                    return error_reply("UNAUTHORIZED", StatusCode::UNAUTHORIZED);
+                }
+                match graph.prune_rooms() {
+                    Ok(removed) => warp::reply::json(&serde_json::json!({ "removed": removed }))
+                        .into_response(),
+                    Err(e) => {
+                        error!("Failed to prune rooms: {}", e);
+                        error_reply("UNABLE_TO_PRUNE_ROOMS", StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                }
+            }))
+        .boxed()
+}
+
 #[tracing::instrument(skip(broadcast_rx))]
-fn websocket(broadcast_rx: Receiver<Option<SSEJson>>) -> BoxedFilter<(impl Reply,)> {
+fn websocket(broadcast_rx: Receiver<Option<WsMessage>>) -> BoxedFilter<(impl Reply,)> {
     warp::path("ws")
         .and(warp::path::end())
         // The `ws()` filter will prepare the Websocket handshake.
@@ -181,7 +377,10 @@ fn websocket(broadcast_rx: Receiver<Option<SSEJson>>) -> BoxedFilter<(impl Reply
 }
 
 #[tracing::instrument(skip(broadcast_rx))]
-async fn do_websocket(websocket: warp::ws::WebSocket, mut broadcast_rx: Receiver<Option<SSEJson>>) {
+async fn do_websocket(
+    websocket: warp::ws::WebSocket,
+    mut broadcast_rx: Receiver<Option<WsMessage>>,
+) {
     // Just echo all messages back...
     let (mut tx, _rx) = websocket.split();
     while broadcast_rx.changed().await.is_ok() {
@@ -202,7 +401,7 @@ async fn do_websocket(websocket: warp::ws::WebSocket, mut broadcast_rx: Receiver
 #[tracing::instrument(skip(graph))]
 async fn relations(graph: Arc<GraphDb>) -> Result<impl Reply, Infallible> {
     let relations = graph.get_json_relations().await;
-    Ok(warp::reply::json(&relations))
+    Ok(warp::reply::json(&*relations))
 }
 
 #[tracing::instrument(skip(graph))]
@@ -210,3 +409,205 @@ async fn servers(graph: Arc<GraphDb>, include_members: bool) -> Result<impl Repl
     let servers = graph.get_servers_json(include_members).await;
     Ok(warp::reply::json(&servers))
 }
+
+/// Resolves a percent-encoded room ID or alias to a room in the graph.
+///
+/// Aliases in the alias index resolve without asking the homeserver.
+async fn resolve_room(cache: &CacheDb, room_id_or_alias: &str) -> Option<String> {
+    let room_id_or_alias = percent_decode_str(room_id_or_alias).decode_utf8().ok()?;
+    if let Some(entry) = cache.aliases.get(&room_id_or_alias) {
+        if cache.graph.knows_room(&entry.room_id) {
+            return Some(entry.room_id);
+        }
+    }
+    cache.graph.resolve_room(&room_id_or_alias).await
+}
+
+/// Looks up a room by its percent-encoded room ID or alias
+#[tracing::instrument(skip(cache))]
+async fn room(cache: CacheDb, room_id_or_alias: String) -> Result<impl Reply, Infallible> {
+    let room = match resolve_room(&cache, &room_id_or_alias).await {
+        Some(room_id) => cache.graph.get_room(&room_id).await,
+        None => None,
+    };
+    match room {
+        Some(room) => Ok(warp::reply::json(&room).into_response()),
+        None => Ok(error_reply("ROOM_NOT_FOUND", StatusCode::NOT_FOUND)),
+    }
+}
+
+#[tracing::instrument(skip(cache))]
+async fn room_aliases(cache: CacheDb, room_id_or_alias: String) -> Result<impl Reply, Infallible> {
+    let room_id = match resolve_room(&cache, &room_id_or_alias).await {
+        Some(room_id) => room_id,
+        None => return Ok(error_reply("ROOM_NOT_FOUND", StatusCode::NOT_FOUND)),
+    };
+    match cache.aliases.get_room_aliases(&room_id) {
+        Ok(aliases) => Ok(warp::reply::json(&RoomAliasesJson { room_id, aliases }).into_response()),
+        Err(e) => {
+            error!("Failed to get aliases: {}", e);
+            Ok(error_reply(
+                "UNABLE_TO_READ_ALIASES",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+#[tracing::instrument(skip(cache))]
+async fn flagged_servers(cache: CacheDb) -> Result<impl Reply, Infallible> {
+    match cache.get_flagged_servers() {
+        Ok(servers) => Ok(warp::reply::with_status(
+            warp::reply::json(&FlaggedServersJson { servers }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to get flagged servers: {}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "UNABLE_TO_READ_FLAGGED_SERVERS"})),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+#[tracing::instrument(skip(cache))]
+async fn version_history(cache: CacheDb, server_name: String) -> Result<impl Reply, Infallible> {
+    let known = cache.contains_server(&server_name)
+        || matches!(cache.get_server_version(&server_name), Ok(Some(_)));
+    if !known {
+        return Ok(error_reply("SERVER_NOT_FOUND", StatusCode::NOT_FOUND));
+    }
+    match cache.get_version_history(&server_name) {
+        Ok(history) => Ok(warp::reply::json(&VersionHistoryJson {
+            server_name,
+            history,
+        })
+        .into_response()),
+        Err(e) => {
+            error!("Failed to get version history: {}", e);
+            Ok(error_reply(
+                "UNABLE_TO_READ_HISTORY",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+fn error_reply(error: &str, status: StatusCode) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": error })),
+        status,
+    )
+    .into_response()
+}
+
+fn csv_reply<T: Serialize>(rows: &[T]) -> Result<warp::reply::Response, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    let body = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(warp::reply::with_header(body, "Content-Type", "text/csv; charset=utf-8").into_response())
+}
+
+/// Loads the versions of all servers and applies the filters of the query
+fn filtered_versions(
+    cache: &CacheDb,
+    query: &VersionsQuery,
+) -> Result<(Vec<ServerVersionJson>, Format), (&'static str, StatusCode)> {
+    let format = match query.format.as_deref() {
+        None | Some("json") => Format::Json,
+        Some("csv") => Format::Csv,
+        Some(_) => return Err(("UNKNOWN_FORMAT", StatusCode::BAD_REQUEST)),
+    };
+    let implementation = match &query.implementation {
+        Some(implementation) => match ServerImplementation::from_str(implementation) {
+            Ok(implementation) => Some(implementation),
+            Err(_) => return Err(("UNKNOWN_IMPLEMENTATION", StatusCode::BAD_REQUEST)),
+        },
+        None => None,
+    };
+
+    let versions = cache.get_all_versions().map_err(|e| {
+        error!("Failed to get versions: {}", e);
+        ("UNABLE_TO_READ_VERSIONS", StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+    let servers = versions
+        .into_iter()
+        .map(|(server_name, server)| ServerVersionJson {
+            server_name,
+            implementation: ServerImplementation::from_name(&server.name),
+            name: server.name,
+            version: server.version,
+        })
+        .filter(|server| match implementation {
+            Some(implementation) => server.implementation == implementation,
+            None => true,
+        })
+        .filter(|server| match &query.version {
+            Some(version) => {
+                server.version == *version || server.version.starts_with(&format!("{}.", version))
+            }
+            None => true,
+        })
+        .collect();
+    Ok((servers, format))
+}
+
+#[tracing::instrument(skip(cache))]
+async fn server_versions(cache: CacheDb, query: VersionsQuery) -> Result<impl Reply, Infallible> {
+    let (servers, format) = match filtered_versions(&cache, &query) {
+        Ok(res) => res,
+        Err((error, status)) => return Ok(error_reply(error, status)),
+    };
+    if format == Format::Csv {
+        return Ok(csv_reply(&servers).unwrap_or_else(|e| {
+            error!("Failed to write versions as csv: {}", e);
+            error_reply("UNABLE_TO_WRITE_CSV", StatusCode::INTERNAL_SERVER_ERROR)
+        }));
+    }
+    Ok(warp::reply::json(&ServerVersionsJson { servers }).into_response())
+}
+
+/// Counts the servers per implementation and per version.
+///
+/// The csv format only contains the counts per version.
+#[tracing::instrument(skip(cache))]
+async fn version_stats(cache: CacheDb, query: VersionsQuery) -> Result<impl Reply, Infallible> {
+    let (servers, format) = match filtered_versions(&cache, &query) {
+        Ok(res) => res,
+        Err((error, status)) => return Ok(error_reply(error, status)),
+    };
+
+    let mut implementations = BTreeMap::new();
+    let mut versions = BTreeMap::new();
+    for server in &servers {
+        *implementations.entry(server.implementation).or_insert(0) += 1;
+        *versions
+            .entry((server.implementation, server.version.clone()))
+            .or_insert(0) += 1;
+    }
+    let mut versions: Vec<VersionCount> = versions
+        .into_iter()
+        .map(|((implementation, version), count)| VersionCount {
+            implementation,
+            version,
+            count,
+        })
+        .collect();
+    versions.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.cmp(b)));
+
+    if format == Format::Csv {
+        return Ok(csv_reply(&versions).unwrap_or_else(|e| {
+            error!("Failed to write version stats as csv: {}", e);
+            error_reply("UNABLE_TO_WRITE_CSV", StatusCode::INTERNAL_SERVER_ERROR)
+        }));
+    }
+    Ok(warp::reply::json(&VersionStatsJson {
+        total: servers.len(),
+        implementations,
+        versions,
+    })
+    .into_response())
+}
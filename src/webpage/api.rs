@@ -1,8 +1,17 @@
+use crate::{
+    database::aliases::AliasEntry,
+    matrix::{FlaggedServer, ServerImplementation, VersionHistoryEntry},
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RelationsJson {
+    /// Increases with every change of the graph
+    pub generation: u64,
     pub nodes: BTreeSet<RoomRelation>,
     pub links: BTreeSet<Link>,
 }
@@ -13,14 +22,45 @@ pub struct SSEJson {
     pub link: Arc<Link>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Ord, Eq, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemovedJson {
+    pub removed_nodes: Vec<String>,
+    pub removed_links: Vec<RemovedLink>,
+}
+
+/// A room whose name, topic, avatar, alias or join rule changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdatedJson {
+    pub updated_node: RoomRelation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemovedLink {
+    pub source: String,
+    pub target: String,
+}
+
+/// Messages sent to the websocket clients
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WsMessage {
+    Added(SSEJson),
+    Removed(RemovedJson),
+    Updated(UpdatedJson),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Ord, Eq, PartialOrd, Hash)]
 pub struct Link {
     pub source: String,
     pub target: String,
+    /// Number of times the target was mentioned in the source
     pub value: i64,
+    /// Unix timestamps in milliseconds. Missing for links found before they were tracked.
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Ord, Eq, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Ord, Eq, PartialOrd, Hash)]
 pub struct RoomRelation {
     pub id: String,
     pub room_id: String,
@@ -33,6 +73,28 @@ pub struct RoomRelation {
     pub incoming_links: Option<i32>,
     pub outgoing_links: Option<i32>,
     pub is_space: bool,
+    pub join_rule: String,
+}
+
+/// A room together with the rooms linking to it and the rooms it links to
+#[derive(Serialize, Debug)]
+pub struct RoomJson {
+    pub node: RoomRelation,
+    pub parents: Vec<NeighbourJson>,
+    pub children: Vec<NeighbourJson>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomAliasesJson {
+    pub room_id: String,
+    pub aliases: Vec<AliasEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NeighbourJson {
+    pub id: String,
+    pub room_id: String,
+    pub link: Link,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,3 +107,41 @@ pub enum Jsonline {
 pub struct ServersJson {
     pub servers: BTreeSet<String>,
 }
+
+#[derive(Serialize, Debug)]
+pub struct VersionHistoryJson {
+    pub server_name: String,
+    pub history: Vec<VersionHistoryEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerVersionJson {
+    pub server_name: String,
+    pub implementation: ServerImplementation,
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerVersionsJson {
+    pub servers: Vec<ServerVersionJson>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VersionCount {
+    pub implementation: ServerImplementation,
+    pub version: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VersionStatsJson {
+    pub total: usize,
+    pub implementations: BTreeMap<ServerImplementation, usize>,
+    pub versions: Vec<VersionCount>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FlaggedServersJson {
+    pub servers: Vec<FlaggedServer>,
+}
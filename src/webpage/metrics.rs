@@ -0,0 +1,148 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    histogram_opts, opts, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Registry,
+};
+use warp::log::Info;
+
+pub static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        histogram_opts!(
+            "http_request_duration_seconds",
+            "Duration of http requests by route"
+        )
+        .namespace("server_stats"),
+        &["route", "method", "status"]
+    )
+    .unwrap()
+});
+
+pub static JOIN_ATTEMPTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!("join_attempts_total", "Attempts to join a room by outcome")
+            .namespace("server_stats"),
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_PERMITS_IN_USE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(opts!(
+        "messages_permits_in_use",
+        "Permits of the messages semaphore which are currently taken"
+    )
+    .namespace("server_stats"))
+    .unwrap()
+});
+
+pub static PAGINATION_PAGES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(opts!(
+        "pagination_pages_total",
+        "Pages of room history fetched by the crawler"
+    )
+    .namespace("server_stats"))
+    .unwrap()
+});
+
+pub static JOB_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        histogram_opts!("job_duration_seconds", "Duration of scheduled jobs")
+            .namespace("server_stats")
+            .buckets(vec![
+                1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0
+            ]),
+        &["job"]
+    )
+    .unwrap()
+});
+
+pub static SERVERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        opts!(
+            "servers",
+            "Known servers by implementation and reachability"
+        )
+        .namespace("server_stats"),
+        &["implementation", "reachability"]
+    )
+    .unwrap()
+});
+
+/// Adds all metrics of this module to the registry used by the exporter
+pub fn register(registry: &Registry) {
+    registry
+        .register(Box::new(HTTP_REQUEST_DURATION.clone()))
+        .expect("Creating a prometheus registry");
+    registry
+        .register(Box::new(JOIN_ATTEMPTS.clone()))
+        .expect("Creating a prometheus registry");
+    registry
+        .register(Box::new(MESSAGES_PERMITS_IN_USE.clone()))
+        .expect("Creating a prometheus registry");
+    registry
+        .register(Box::new(PAGINATION_PAGES.clone()))
+        .expect("Creating a prometheus registry");
+    registry
+        .register(Box::new(JOB_DURATION.clone()))
+        .expect("Creating a prometheus registry");
+    registry
+        .register(Box::new(SERVERS.clone()))
+        .expect("Creating a prometheus registry");
+}
+
+/// Counts a taken permit of the messages semaphore until it gets dropped
+pub struct PermitInUse(());
+
+/// Call this right after acquiring a permit and keep the result next to it
+pub fn permit_in_use() -> PermitInUse {
+    MESSAGES_PERMITS_IN_USE.inc();
+    PermitInUse(())
+}
+
+impl Drop for PermitInUse {
+    fn drop(&mut self) {
+        MESSAGES_PERMITS_IN_USE.dec();
+    }
+}
+
+/// Maps a request path to the route it was handled by to keep the label cardinality low
+fn route_label(path: &str) -> &'static str {
+    let path = path.trim_start_matches('/');
+    let mut segments = path.split('/');
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some(""), _, _)
+        | (Some("spaces"), None, _)
+        | (Some("links"), None, _)
+        | (Some("3d"), None, _)
+        | (Some("faq"), None, _)
+        | (Some("api"), None, _) => "index",
+        (Some("metrics"), None, _) => "metrics",
+        (Some("ws"), None, _) => "ws",
+        (Some("health"), None, _) => "health",
+        (Some("relations"), None, _) => "relations",
+        (Some("servers"), None, _) => "servers",
+        (Some("api"), Some("v0.1.0"), Some("3d-data")) => "api_3d_data",
+        (Some("api"), Some("v0.1.0"), Some("versions")) => "api_versions",
+        (Some("api"), Some("v0.1.0"), Some("version-stats")) => "api_version_stats",
+        (Some("api"), Some("v0.1.0"), Some("outdated")) => "api_outdated",
+        (Some("api"), Some("v0.1.0"), Some("rooms")) => "api_rooms",
+        (Some("api"), _, _) => "api_other",
+        (Some("_matrix"), _, _)
+        | (Some("transactions"), _, _)
+        | (Some("users"), _, _)
+        | (Some("rooms"), _, _) => "appservice",
+        _ => "static",
+    }
+}
+
+/// Used as a warp log filter to record the latency and status of every request
+pub fn record_request(info: Info) {
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[
+            route_label(info.path()),
+            info.method().as_str(),
+            info.status().as_str(),
+        ])
+        .observe(info.elapsed().as_secs_f64());
+}
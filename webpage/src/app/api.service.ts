@@ -1,7 +1,7 @@
 import { HttpClient, HttpHeaders } from '@angular/common/http';
 import { Injectable } from '@angular/core';
 import { Observable } from 'rxjs';
-import { GraphWebsocketService, WsMessage } from './graph-websocket.service';
+import { GraphWebsocketService, WsMessage, WsRemovedMessage, WsUpdatedMessage } from './graph-websocket.service';
 
 export interface Row {
   id: string;
@@ -12,9 +12,11 @@ export interface Row {
   members: number;
   weight: string;
   is_space: boolean;
+  join_rule: string;
   updated?: boolean;
 }
 export interface APIData {
+  generation?: number,
   nodes: Row[],
   links: any[]
 }
@@ -39,6 +41,16 @@ export class ApiService {
           this.websocket.connect();
 
           this.websocket.messages?.subscribe((event: WsMessage) => {
+            if ('removed_nodes' in event) {
+              this.data = this.remove(this.data as APIData, event);
+              observer.next(this.data);
+              return;
+            }
+            if ('updated_node' in event) {
+              this.data = this.update(this.data as APIData, event);
+              observer.next(this.data);
+              return;
+            }
             const data = event;
             let graph_data = this.data;
             let nodes_new = this.difference_nodes(graph_data?.nodes as Row[], data.node);
@@ -59,6 +71,35 @@ export class ApiService {
     }));
   }
 
+  remove(old: APIData, removed: WsRemovedMessage): APIData {
+    return {
+      nodes: old.nodes.filter(node => !removed.removed_nodes.includes(node.id)),
+      links: old.links.filter(link => {
+        // The graph library replaces the ids of links with the node objects
+        const source = link.source.id ?? link.source;
+        const target = link.target.id ?? link.target;
+        return !removed.removed_nodes.includes(source) && !removed.removed_nodes.includes(target)
+          && !removed.removed_links.some(removed_link => removed_link.source == source && removed_link.target == target);
+      })
+    };
+  }
+
+  update(old: APIData, updated: WsUpdatedMessage): APIData {
+    const node = updated.updated_node;
+    return {
+      // Keep the link counts and the position the graph library added to the node
+      nodes: old.nodes.map(old_node => old_node.id == node.id ? Object.assign(old_node, {
+        name: node.name,
+        alias: node.alias,
+        avatar: node.avatar,
+        topic: node.topic,
+        is_space: node.is_space,
+        join_rule: node.join_rule,
+      }) : old_node),
+      links: old.links
+    };
+  }
+
   difference_nodes(old: Row[], newd: Row) {
     if (!old.some(node_old => node_old.id == newd.id)) {
       return [newd];
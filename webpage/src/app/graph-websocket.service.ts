@@ -5,10 +5,21 @@ import { webSocket, WebSocketSubject } from 'rxjs/webSocket';
 import { Row } from './api.service';
 
 
-export interface WsMessage {
+export interface WsAddedMessage {
   node: Row;
   link: any;
 }
+
+export interface WsRemovedMessage {
+  removed_nodes: string[];
+  removed_links: { source: string, target: string }[];
+}
+
+export interface WsUpdatedMessage {
+  updated_node: Row;
+}
+
+export type WsMessage = WsAddedMessage | WsRemovedMessage | WsUpdatedMessage;
 @Injectable({
   providedIn: 'root'
 })